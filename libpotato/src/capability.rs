@@ -0,0 +1,234 @@
+use nix::errno::Errno;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    Chown,
+    DacOverride,
+    DacReadSearch,
+    Fowner,
+    Fsetid,
+    Kill,
+    Setgid,
+    Setuid,
+    Setpcap,
+    LinuxImmutable,
+    NetBindService,
+    NetBroadcast,
+    NetAdmin,
+    NetRaw,
+    IpcLock,
+    IpcOwner,
+    SysModule,
+    SysRawio,
+    SysChroot,
+    SysPtrace,
+    SysPacct,
+    SysAdmin,
+    SysBoot,
+    SysNice,
+    SysResource,
+    SysTime,
+    SysTtyConfig,
+    Mknod,
+    Lease,
+    AuditWrite,
+    AuditControl,
+    Setfcap,
+    MacOverride,
+    MacAdmin,
+    Syslog,
+    WakeAlarm,
+    BlockSuspend,
+    AuditRead,
+    Perfmon,
+    Bpf,
+    CheckpointRestore,
+}
+
+impl Capability {
+    /// every capability, in the order of their numbers
+    pub const ALL: [Capability; 41] = [
+        Capability::Chown,
+        Capability::DacOverride,
+        Capability::DacReadSearch,
+        Capability::Fowner,
+        Capability::Fsetid,
+        Capability::Kill,
+        Capability::Setgid,
+        Capability::Setuid,
+        Capability::Setpcap,
+        Capability::LinuxImmutable,
+        Capability::NetBindService,
+        Capability::NetBroadcast,
+        Capability::NetAdmin,
+        Capability::NetRaw,
+        Capability::IpcLock,
+        Capability::IpcOwner,
+        Capability::SysModule,
+        Capability::SysRawio,
+        Capability::SysChroot,
+        Capability::SysPtrace,
+        Capability::SysPacct,
+        Capability::SysAdmin,
+        Capability::SysBoot,
+        Capability::SysNice,
+        Capability::SysResource,
+        Capability::SysTime,
+        Capability::SysTtyConfig,
+        Capability::Mknod,
+        Capability::Lease,
+        Capability::AuditWrite,
+        Capability::AuditControl,
+        Capability::Setfcap,
+        Capability::MacOverride,
+        Capability::MacAdmin,
+        Capability::Syslog,
+        Capability::WakeAlarm,
+        Capability::BlockSuspend,
+        Capability::AuditRead,
+        Capability::Perfmon,
+        Capability::Bpf,
+        Capability::CheckpointRestore,
+    ];
+
+    /// inverse of `to_name`, e.g. `Capability::NetBindService` for
+    /// `net_bind_service`
+    pub fn from_name(name: &str) -> Option<Capability> {
+        Capability::ALL
+            .iter()
+            .find(|cap| cap.to_name() == name)
+            .copied()
+    }
+
+    /// name without the `CAP_` prefix, in lowercase
+    pub fn to_name(&self) -> &str {
+        match self {
+            Capability::Chown => "chown",
+            Capability::DacOverride => "dac_override",
+            Capability::DacReadSearch => "dac_read_search",
+            Capability::Fowner => "fowner",
+            Capability::Fsetid => "fsetid",
+            Capability::Kill => "kill",
+            Capability::Setgid => "setgid",
+            Capability::Setuid => "setuid",
+            Capability::Setpcap => "setpcap",
+            Capability::LinuxImmutable => "linux_immutable",
+            Capability::NetBindService => "net_bind_service",
+            Capability::NetBroadcast => "net_broadcast",
+            Capability::NetAdmin => "net_admin",
+            Capability::NetRaw => "net_raw",
+            Capability::IpcLock => "ipc_lock",
+            Capability::IpcOwner => "ipc_owner",
+            Capability::SysModule => "sys_module",
+            Capability::SysRawio => "sys_rawio",
+            Capability::SysChroot => "sys_chroot",
+            Capability::SysPtrace => "sys_ptrace",
+            Capability::SysPacct => "sys_pacct",
+            Capability::SysAdmin => "sys_admin",
+            Capability::SysBoot => "sys_boot",
+            Capability::SysNice => "sys_nice",
+            Capability::SysResource => "sys_resource",
+            Capability::SysTime => "sys_time",
+            Capability::SysTtyConfig => "sys_tty_config",
+            Capability::Mknod => "mknod",
+            Capability::Lease => "lease",
+            Capability::AuditWrite => "audit_write",
+            Capability::AuditControl => "audit_control",
+            Capability::Setfcap => "setfcap",
+            Capability::MacOverride => "mac_override",
+            Capability::MacAdmin => "mac_admin",
+            Capability::Syslog => "syslog",
+            Capability::WakeAlarm => "wake_alarm",
+            Capability::BlockSuspend => "block_suspend",
+            Capability::AuditRead => "audit_read",
+            Capability::Perfmon => "perfmon",
+            Capability::Bpf => "bpf",
+            Capability::CheckpointRestore => "checkpoint_restore",
+        }
+    }
+
+    /// number of the capability, e.g. 10 for `CAP_NET_BIND_SERVICE`
+    pub fn number(&self) -> u32 {
+        *self as u32
+    }
+}
+
+/// `_LINUX_CAPABILITY_VERSION_3`, 64 bit sets split over two `CapData`
+const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Securebits locked by `drop_all_except`: uid 0 gains no capability over
+/// `execve`, changing uids keeps clearing them and ambient ones can't be raised
+const SECUREBITS: libc::c_int = libc::SECBIT_NOROOT
+    | libc::SECBIT_NOROOT_LOCKED
+    | libc::SECBIT_NO_SETUID_FIXUP_LOCKED
+    | libc::SECBIT_KEEP_CAPS_LOCKED
+    | libc::SECBIT_NO_CAP_AMBIENT_RAISE
+    | libc::SECBIT_NO_CAP_AMBIENT_RAISE_LOCKED;
+
+/// Set `PR_SET_NO_NEW_PRIVS`, lock the securebits and drop every capability
+/// of the calling thread but `retained` from its bounding, ambient,
+/// effective, permitted and inheritable sets. Retained capabilities are
+/// not passed on over `execve`, even as uid 0, nor gained back from setuid
+/// or file capabilities.
+pub fn drop_all_except(retained: &[Capability]) -> Result<(), nix::Error> {
+    let res = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    Errno::result(res)?;
+
+    // takes CAP_SETPCAP as well
+    let res = unsafe { libc::prctl(libc::PR_SET_SECUREBITS, SECUREBITS, 0, 0, 0) };
+    Errno::result(res)?;
+
+    // the running kernel may know more capabilities than `Capability`,
+    // it fails with EINVAL past the last one
+    for cap in 0..64 {
+        if retained.iter().any(|retained| retained.number() == cap) {
+            continue;
+        }
+        let res = unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) };
+        match Errno::result(res) {
+            Ok(_) => {}
+            Err(nix::Error::Sys(Errno::EINVAL)) => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    let res = unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL,
+            0,
+            0,
+            0,
+        )
+    };
+    Errno::result(res)?;
+
+    // dropped last, dropping from the bounding set takes CAP_SETPCAP
+    let mut data = [CapData::default(); 2];
+    for cap in retained {
+        let set = &mut data[cap.number() as usize / 32];
+        let bit = 1 << (cap.number() % 32);
+        set.effective |= bit;
+        set.permitted |= bit;
+    }
+    let mut header = CapHeader {
+        version: CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let res = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) };
+    Errno::result(res).map(drop)
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Resources used by the processes of a cgroup over its lifetime
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Usage {
+    /// `usage_usec` of `cpu.stat`
+    pub cpu: Duration,
+    /// `user_usec` of `cpu.stat`
+    pub cpu_user: Duration,
+    /// `system_usec` of `cpu.stat`
+    pub cpu_system: Duration,
+    /// `memory.peak` in bytes, `None` without the memory controller or before linux 5.19
+    pub memory_peak: Option<u64>,
+    /// `rbytes` of `io.stat` summed over devices, `None` without the io controller
+    pub io_read: Option<u64>,
+    /// `wbytes` of `io.stat` summed over devices, `None` without the io controller
+    pub io_write: Option<u64>,
+}
+
+/// A cgroup v2 directory
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// create the cgroup `name` under `parent`, replacing an empty
+    /// leftover of the same name
+    pub fn create(parent: &Path, name: &str) -> Result<Cgroup, io::Error> {
+        let path = parent.join(name);
+        match fs::create_dir(&path) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                fs::remove_dir(&path)?;
+                fs::create_dir(&path)?;
+            }
+            res => res?,
+        }
+        Ok(Cgroup { path })
+    }
+
+    /// handle on the existing cgroup at `path`
+    pub fn open(path: &Path) -> Cgroup {
+        Cgroup {
+            path: path.to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// write `value` to the interface file `file`, e.g. `memory.max`
+    pub fn write(&self, file: &str, value: &str) -> Result<(), io::Error> {
+        fs::write(self.path.join(file), value)
+    }
+
+    /// read the interface file `file`, e.g. `cpu.stat`
+    pub fn read(&self, file: &str) -> Result<String, io::Error> {
+        fs::read_to_string(self.path.join(file))
+    }
+
+    /// move the process `pid` into the cgroup, its threads go along
+    pub fn add_process(&self, pid: i32) -> Result<(), io::Error> {
+        self.write("cgroup.procs", &pid.to_string())
+    }
+
+    /// read the resources used by the cgroup from `cpu.stat`,
+    /// `memory.peak` and `io.stat`
+    pub fn usage(&self) -> Result<Usage, io::Error> {
+        let mut usage = Usage::default();
+        for (key, value) in stat_pairs(&self.read("cpu.stat")?) {
+            let micros = Duration::from_micros(value);
+            match key {
+                "usage_usec" => usage.cpu = micros,
+                "user_usec" => usage.cpu_user = micros,
+                "system_usec" => usage.cpu_system = micros,
+                _ => {}
+            }
+        }
+        usage.memory_peak = self
+            .read("memory.peak")
+            .ok()
+            .and_then(|peak| peak.trim().parse().ok());
+        if let Ok(stat) = self.read("io.stat") {
+            let (mut read, mut write) = (0, 0);
+            // one line per device, e.g. `8:0 rbytes=1024 wbytes=0 rios=1 ...`
+            for line in stat.lines() {
+                for field in line.split_whitespace().skip(1) {
+                    match field.split_once('=') {
+                        Some(("rbytes", n)) => read += n.parse().unwrap_or(0),
+                        Some(("wbytes", n)) => write += n.parse().unwrap_or(0),
+                        _ => {}
+                    }
+                }
+            }
+            usage.io_read = Some(read);
+            usage.io_write = Some(write);
+        }
+        Ok(usage)
+    }
+
+    /// SIGKILL every process in the cgroup, requires linux 5.14
+    pub fn kill(&self) -> Result<(), io::Error> {
+        self.write("cgroup.kill", "1")
+    }
+
+    /// remove the cgroup, fails while a process is still in it
+    pub fn remove(&self) -> Result<(), io::Error> {
+        fs::remove_dir(&self.path)
+    }
+}
+
+/// parse the `key value` lines of a flat keyed file like `cpu.stat`
+fn stat_pairs(stat: &str) -> impl Iterator<Item = (&str, u64)> {
+    stat.lines().filter_map(|line| {
+        let (key, value) = line.split_once(' ')?;
+        Some((key, value.trim().parse().ok()?))
+    })
+}
+
+/// controllers the cgroup at `path` can enable for its children
+pub fn available_controllers(path: &Path) -> Result<Vec<String>, io::Error> {
+    let controllers = fs::read_to_string(path.join("cgroup.controllers"))?;
+    Ok(controllers.split_whitespace().map(str::to_string).collect())
+}
+
+/// enable `controllers`, e.g. `memory`, for the children of the cgroup
+/// at `path`. The cgroup must not have any process of its own.
+pub fn enable_controllers(path: &Path, controllers: &[&str]) -> Result<(), io::Error> {
+    let enable: Vec<String> = controllers.iter().map(|c| format!("+{}", c)).collect();
+    fs::write(path.join("cgroup.subtree_control"), enable.join(" "))
+}
@@ -1,5 +1,7 @@
 use libc;
 use nix::errno::{errno, Errno};
+use std::os::unix::io::RawFd;
+use std::ptr;
 
 extern "C" fn clone_cb<F>(data: *mut libc::c_void) -> libc::c_int
 where
@@ -11,6 +13,20 @@ where
 
 /// unsafe wrapper around libc clone
 pub unsafe fn clone<F>(f: F, stack: &mut [u8], flags: libc::c_int) -> libc::c_int
+where
+    F: FnOnce() -> isize,
+{
+    clone_with_ptid(f, stack, flags, ptr::null_mut())
+}
+
+/// same as `clone` but pass `ptid`, where the kernel stores the child tid
+/// with CLONE_PARENT_SETTID or the pidfd with CLONE_PIDFD
+unsafe fn clone_with_ptid<F>(
+    f: F,
+    stack: &mut [u8],
+    flags: libc::c_int,
+    ptid: *mut libc::c_int,
+) -> libc::c_int
 where
     F: FnOnce() -> isize,
 {
@@ -25,6 +41,7 @@ where
         stack_ptr_aligned as *mut libc::c_void,
         flags,
         fn_ptr,
+        ptid,
     )
 }
 
@@ -41,6 +58,24 @@ where
     }
 }
 
+/// same as `clone_proc_newns` but also return a pidfd referring to the new process,
+/// requires linux 5.2. The caller owns the pidfd and has to close it
+pub fn clone_proc_newns_pidfd<F>(
+    f: F,
+    stack: &mut [u8],
+    flags: libc::c_int,
+) -> Result<(libc::c_int, RawFd), Errno>
+where
+    F: FnOnce() -> isize,
+{
+    let mask = !(libc::CLONE_VM | libc::CLONE_THREAD | libc::CLONE_PARENT_SETTID);
+    let mut pidfd: libc::c_int = -1;
+    match unsafe { clone_with_ptid(f, stack, (flags & mask) | libc::CLONE_PIDFD, &mut pidfd) } {
+        -1 => Err(Errno::from_i32(errno())),
+        pid => Ok((pid, pidfd)),
+    }
+}
+
 /// create new thread to do task and create new namespaces specified in flags,
 /// silently ignore CLONE_NEWUSER and CLONE_NEWPID due to compatibility with CLONE_VM
 pub fn clone_thread_newns<F>(
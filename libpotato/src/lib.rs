@@ -2,8 +2,11 @@ pub use libc;
 pub use nix;
 pub use signal_hook;
 
+pub mod capability;
+pub mod cgroup;
 pub mod clone;
 pub mod idmap;
 pub mod namespace;
 pub mod net;
+pub mod seccomp;
 pub mod signal;
@@ -3,7 +3,7 @@ use std::fs::OpenOptions;
 use std::io;
 use std::os::unix::io::{IntoRawFd, RawFd};
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Namespace {
     Cgroup,
     Ipc,
@@ -15,6 +15,25 @@ pub enum Namespace {
 }
 
 impl Namespace {
+    /// every kind of namespace
+    pub const ALL: [Namespace; 7] = [
+        Namespace::Cgroup,
+        Namespace::Ipc,
+        Namespace::Network,
+        Namespace::Mount,
+        Namespace::Pid,
+        Namespace::User,
+        Namespace::Uts,
+    ];
+
+    /// inverse of `to_symlink_name`, e.g. `net` for `Namespace::Network`
+    pub fn from_symlink_name(name: &str) -> Option<Namespace> {
+        Namespace::ALL
+            .iter()
+            .find(|ns| ns.to_symlink_name() == name)
+            .copied()
+    }
+
     pub fn to_symlink_name(&self) -> &str {
         match self {
             Namespace::Cgroup => "cgroup",
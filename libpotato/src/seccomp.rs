@@ -0,0 +1,598 @@
+use libc::{c_int, c_long, c_void, siginfo_t, sock_filter, sock_fprog};
+use nix::errno::Errno;
+use nix::sys::signal::{self, sigaction, SaFlags, SigAction, SigHandler, SigSet};
+use std::collections::BTreeSet;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// syscalls a handler has no business making, denied by `Profile::deny_list`
+pub const DEFAULT_DENIED: &[&str] = &[
+    "acct",
+    "add_key",
+    "bpf",
+    "delete_module",
+    "finit_module",
+    "init_module",
+    "io_uring_enter",
+    "io_uring_register",
+    "io_uring_setup",
+    "ioperm",
+    "iopl",
+    "kexec_file_load",
+    "kexec_load",
+    "keyctl",
+    "mount",
+    "name_to_handle_at",
+    "open_by_handle_at",
+    "perf_event_open",
+    "pivot_root",
+    "process_vm_readv",
+    "process_vm_writev",
+    "ptrace",
+    "quotactl",
+    "reboot",
+    "request_key",
+    "setns",
+    "swapoff",
+    "swapon",
+    "syslog",
+    "umount2",
+    "unshare",
+    "userfaultfd",
+];
+
+/// syscalls every allow list includes, the worker needs them to
+/// send the response and exit once the handler returned, or to
+/// abort when it panicked
+pub const BASELINE: &[&str] = &[
+    "brk",
+    "clock_gettime",
+    "close",
+    "exit",
+    "exit_group",
+    // debug builds check a file descriptor is open before closing it
+    "fcntl",
+    "futex",
+    "getcwd",
+    "getpid",
+    "gettid",
+    "madvise",
+    "mmap",
+    "mremap",
+    "munmap",
+    "restart_syscall",
+    "rt_sigaction",
+    "rt_sigprocmask",
+    "rt_sigreturn",
+    "sched_yield",
+    "sigaltstack",
+    "tgkill",
+    "write",
+    "writev",
+];
+
+/// What a syscall the profile doesn't let through does
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// fail with the errno, e.g. `EPERM`
+    Errno(i32),
+    /// kill the process with SIGSYS
+    Kill,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Filter {
+    Deny(BTreeSet<c_long>),
+    Allow(BTreeSet<c_long>),
+}
+
+/// seccomp-bpf filter a process installs on itself. Violations are logged
+/// to the audit log, the kernel logs kills even without it.
+///
+/// Whatever the profile, `clone3` fails with `ENOSYS`, its flags can't be
+/// filtered so callers fall back to `clone`, and `clone` creating a
+/// namespace is a violation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Profile {
+    filter: Filter,
+    action: Action,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::deny_list()
+    }
+}
+
+impl Profile {
+    /// let through everything but `DEFAULT_DENIED`, which fails with `EPERM`
+    pub fn deny_list() -> Profile {
+        // some of them only exist on some architectures
+        let denied = DEFAULT_DENIED.iter().filter_map(|name| syscall_number(name));
+        Profile {
+            filter: Filter::Deny(denied.collect()),
+            action: Action::Errno(libc::EPERM),
+        }
+    }
+
+    /// only let through `names` and `BASELINE`, anything else fails
+    /// with `EPERM`. `Err` with the first name that is not a syscall.
+    pub fn allow_list<'a>(names: &[&'a str]) -> Result<Profile, &'a str> {
+        let mut allowed = BTreeSet::new();
+        for name in names {
+            allowed.insert(syscall_number(name).ok_or(*name)?);
+        }
+        for name in BASELINE {
+            allowed.insert(syscall_number(name).unwrap()); // safe unwrap
+        }
+        Ok(Profile {
+            filter: Filter::Allow(allowed),
+            action: Action::Errno(libc::EPERM),
+        })
+    }
+
+    /// do `action` on the syscalls the profile doesn't let through
+    pub fn with_action(mut self, action: Action) -> Profile {
+        self.action = action;
+        self
+    }
+
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    /// Install the filter on the calling thread, for good. Sets
+    /// `PR_SET_NO_NEW_PRIVS`, which unprivileged processes need to.
+    ///
+    /// With `violations`, the syscalls failed by an `Action::Errno` are
+    /// also written to that pipe, see `read_violations`. They are trapped
+    /// by a SIGSYS handler of the process, which must keep it installed.
+    pub fn install(&self, violations: Option<RawFd>) -> Result<(), nix::Error> {
+        let trap = match (self.action, violations) {
+            (Action::Errno(_), Some(fd)) => {
+                VIOLATIONS.store(fd, Ordering::Relaxed);
+                let handler = SigHandler::SigAction(trap_violation);
+                let sigact = SigAction::new(handler, SaFlags::SA_SIGINFO, SigSet::empty());
+                unsafe { sigaction(signal::SIGSYS, &sigact) }?;
+                // a blocked SIGSYS would kill the process instead
+                let mut sigset = SigSet::empty();
+                sigset.add(signal::SIGSYS);
+                signal::sigprocmask(signal::SigmaskHow::SIG_UNBLOCK, Some(&sigset), None)?;
+                true
+            }
+            _ => false,
+        };
+        let mut program = self.program(trap);
+        let prog = sock_fprog {
+            len: program.len() as u16,
+            filter: program.as_mut_ptr(),
+        };
+        let res = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+        Errno::result(res)?;
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_LOG,
+                &prog as *const sock_fprog,
+            )
+        };
+        Errno::result(res).map(drop)
+    }
+
+    /// BPF program of the filter, run on `struct seccomp_data`. With `trap`
+    /// an `Action::Errno` raises SIGSYS carrying the errno instead.
+    fn program(&self, trap: bool) -> Vec<sock_filter> {
+        let violation = match self.action {
+            Action::Errno(errno) if trap => {
+                libc::SECCOMP_RET_TRAP | (errno as u32 & libc::SECCOMP_RET_DATA)
+            }
+            Action::Errno(errno) => {
+                libc::SECCOMP_RET_ERRNO | (errno as u32 & libc::SECCOMP_RET_DATA)
+            }
+            Action::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+        };
+        let (syscalls, matched, unmatched) = match &self.filter {
+            Filter::Deny(syscalls) => (syscalls, violation, libc::SECCOMP_RET_ALLOW),
+            Filter::Allow(syscalls) => (syscalls, libc::SECCOMP_RET_ALLOW, violation),
+        };
+
+        let mut program = vec![
+            // syscall numbers differ between architectures
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_ARCH),
+            jump(libc::BPF_JEQ, AUDIT_ARCH, 1, 0),
+            stmt(libc::BPF_RET, libc::SECCOMP_RET_KILL_PROCESS),
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_NR),
+        ];
+        if cfg!(target_arch = "x86_64") {
+            // x32 syscalls share the architecture, with this bit set
+            program.push(jump(libc::BPF_JGE, X32_SYSCALL_BIT, 0, 1));
+            program.push(stmt(libc::BPF_RET, violation));
+        }
+        program.extend_from_slice(&[
+            jump(libc::BPF_JEQ, libc::SYS_clone3 as u32, 0, 1),
+            stmt(libc::BPF_RET, libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32),
+            jump(libc::BPF_JEQ, libc::SYS_clone as u32, 0, 3),
+            // the flags are the first argument on every architecture
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_ARG0),
+            jump(BPF_JSET, CLONE_NEW_FLAGS, 0, 1),
+            stmt(libc::BPF_RET, violation),
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_NR),
+        ]);
+        for nr in syscalls {
+            program.push(jump(libc::BPF_JEQ, *nr as u32, 0, 1));
+            program.push(stmt(libc::BPF_RET, matched));
+        }
+        program.push(stmt(libc::BPF_RET, unmatched));
+        program
+    }
+}
+
+/// offsets in `struct seccomp_data`, the argument is its lower half
+/// on these little endian architectures
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+const SECCOMP_DATA_ARG0: u32 = 16;
+
+/// missing from libc
+const BPF_JSET: u32 = 0x40;
+
+/// `clone` flags creating a namespace, `CLONE_NEWTIME` is only
+/// known to `clone3` and clashes with the exit signal of `clone`
+const CLONE_NEW_FLAGS: u32 = (libc::CLONE_NEWNS
+    | libc::CLONE_NEWCGROUP
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET) as u32;
+
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+fn stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(cond: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: (libc::BPF_JMP | cond | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// pipe `trap_violation` reports on
+static VIOLATIONS: AtomicI32 = AtomicI32::new(-1);
+
+/// `siginfo_t` of a SIGSYS raised by `SECCOMP_RET_TRAP`, `errno`
+/// holds the data of the return value
+#[repr(C)]
+struct SigsysInfo {
+    signo: c_int,
+    errno: c_int,
+    code: c_int,
+    call_addr: *mut c_void,
+    syscall: c_int,
+    arch: u32,
+}
+
+/// Fail the trapped syscall with the errno of the filter, and write its
+/// number to `VIOLATIONS`. A full pipe drops it rather than block.
+extern "C" fn trap_violation(_: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    let info = unsafe { &*(info as *const SigsysInfo) };
+    let ret = -(info.errno as i64);
+    let context = unsafe { &mut *(context as *mut libc::ucontext_t) };
+    #[cfg(target_arch = "x86_64")]
+    {
+        context.uc_mcontext.gregs[libc::REG_RAX as usize] = ret;
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        context.uc_mcontext.regs[0] = ret as u64;
+    }
+    let nr = info.syscall;
+    let fd = VIOLATIONS.load(Ordering::Relaxed);
+    unsafe { libc::write(fd, &nr as *const c_int as *const c_void, mem::size_of::<c_int>()) };
+}
+
+/// Drain the syscall numbers written to the non-blocking pipe at `fd`
+/// by a profile installed with it, in the order they were denied
+pub fn read_violations(fd: RawFd) -> Vec<c_long> {
+    let mut violations = Vec::new();
+    let mut nr: c_int = 0;
+    let size = mem::size_of::<c_int>();
+    while unsafe { libc::read(fd, &mut nr as *mut c_int as *mut c_void, size) } == size as isize {
+        violations.push(nr as c_long);
+    }
+    violations
+}
+
+/// name of the syscall `nr` on this architecture, inverse of `syscall_number`
+pub fn syscall_name(nr: c_long) -> Option<&'static str> {
+    SYSCALLS
+        .iter()
+        .find(|(_, sys_nr)| *sys_nr == nr)
+        .map(|(sys_name, _)| &sys_name[4..])
+}
+
+/// number of the syscall `name` on this architecture, e.g. `ptrace`
+pub fn syscall_number(name: &str) -> Option<c_long> {
+    SYSCALLS
+        .iter()
+        .find(|(sys_name, _)| &sys_name[4..] == name)
+        .map(|(_, nr)| *nr)
+}
+
+macro_rules! syscalls {
+    ($($nr:ident),* $(,)?) => {
+        &[$((stringify!($nr), libc::$nr)),*]
+    };
+}
+
+/// syscalls known by name, as `SYS_` constant names
+#[cfg(target_arch = "x86_64")]
+const SYSCALLS: &[(&str, c_long)] = syscalls![
+    SYS_read, SYS_write, SYS_open, SYS_close, SYS_stat, SYS_fstat, SYS_lstat, SYS_poll,
+    SYS_lseek, SYS_mmap, SYS_mprotect, SYS_munmap, SYS_brk, SYS_rt_sigaction,
+    SYS_rt_sigprocmask, SYS_rt_sigreturn, SYS_ioctl, SYS_pread64, SYS_pwrite64, SYS_readv,
+    SYS_writev, SYS_access, SYS_pipe, SYS_select, SYS_sched_yield, SYS_mremap, SYS_msync,
+    SYS_mincore, SYS_madvise, SYS_dup, SYS_dup2, SYS_pause, SYS_nanosleep, SYS_getitimer,
+    SYS_alarm, SYS_setitimer, SYS_getpid, SYS_sendfile, SYS_socket, SYS_connect, SYS_accept,
+    SYS_sendto, SYS_recvfrom, SYS_sendmsg, SYS_recvmsg, SYS_shutdown, SYS_bind, SYS_listen,
+    SYS_getsockname, SYS_getpeername, SYS_socketpair, SYS_setsockopt, SYS_getsockopt,
+    SYS_clone, SYS_fork, SYS_vfork, SYS_execve, SYS_exit, SYS_wait4, SYS_kill, SYS_uname,
+    SYS_fcntl, SYS_flock, SYS_fsync, SYS_fdatasync, SYS_truncate, SYS_ftruncate,
+    SYS_getdents, SYS_getcwd, SYS_chdir, SYS_fchdir, SYS_rename, SYS_mkdir, SYS_rmdir,
+    SYS_creat, SYS_link, SYS_unlink, SYS_symlink, SYS_readlink, SYS_chmod, SYS_fchmod,
+    SYS_chown, SYS_fchown, SYS_lchown, SYS_umask, SYS_gettimeofday, SYS_getrlimit,
+    SYS_getrusage, SYS_sysinfo, SYS_times, SYS_ptrace, SYS_getuid, SYS_syslog, SYS_getgid,
+    SYS_setuid, SYS_setgid, SYS_geteuid, SYS_getegid, SYS_setpgid, SYS_getppid,
+    SYS_getpgrp, SYS_setsid, SYS_getgroups, SYS_setgroups, SYS_setresuid, SYS_getresuid,
+    SYS_setresgid, SYS_getresgid, SYS_getpgid, SYS_getsid, SYS_capget, SYS_capset,
+    SYS_rt_sigpending, SYS_rt_sigtimedwait, SYS_rt_sigsuspend, SYS_sigaltstack,
+    SYS_mknod, SYS_statfs, SYS_fstatfs, SYS_getpriority, SYS_setpriority, SYS_mlock,
+    SYS_munlock, SYS_pivot_root, SYS_prctl, SYS_arch_prctl, SYS_setrlimit, SYS_chroot,
+    SYS_sync, SYS_acct, SYS_mount, SYS_umount2, SYS_swapon, SYS_swapoff, SYS_reboot,
+    SYS_sethostname, SYS_iopl, SYS_ioperm, SYS_init_module, SYS_delete_module,
+    SYS_quotactl, SYS_gettid, SYS_getxattr, SYS_fgetxattr, SYS_tkill, SYS_time, SYS_futex,
+    SYS_sched_setaffinity, SYS_sched_getaffinity, SYS_epoll_create, SYS_getdents64,
+    SYS_set_tid_address, SYS_restart_syscall, SYS_fadvise64, SYS_clock_gettime,
+    SYS_clock_getres, SYS_clock_nanosleep, SYS_exit_group, SYS_epoll_wait, SYS_epoll_ctl,
+    SYS_tgkill, SYS_waitid, SYS_add_key, SYS_request_key, SYS_keyctl,
+    SYS_inotify_init, SYS_inotify_add_watch, SYS_inotify_rm_watch, SYS_openat,
+    SYS_mkdirat, SYS_fchownat, SYS_newfstatat, SYS_unlinkat, SYS_renameat, SYS_linkat,
+    SYS_symlinkat, SYS_readlinkat, SYS_fchmodat, SYS_faccessat, SYS_pselect6, SYS_ppoll,
+    SYS_unshare, SYS_set_robust_list, SYS_get_robust_list, SYS_splice, SYS_tee,
+    SYS_vmsplice, SYS_utimensat, SYS_epoll_pwait, SYS_timerfd_create, SYS_eventfd,
+    SYS_fallocate, SYS_timerfd_settime, SYS_timerfd_gettime, SYS_accept4, SYS_eventfd2,
+    SYS_epoll_create1, SYS_dup3, SYS_pipe2, SYS_inotify_init1, SYS_preadv, SYS_pwritev,
+    SYS_perf_event_open, SYS_recvmmsg, SYS_prlimit64, SYS_name_to_handle_at,
+    SYS_open_by_handle_at, SYS_sendmmsg, SYS_setns, SYS_getcpu, SYS_process_vm_readv,
+    SYS_process_vm_writev, SYS_finit_module, SYS_renameat2, SYS_seccomp, SYS_getrandom,
+    SYS_memfd_create, SYS_kexec_load, SYS_kexec_file_load, SYS_bpf, SYS_execveat,
+    SYS_userfaultfd, SYS_membarrier, SYS_mlock2, SYS_copy_file_range, SYS_preadv2,
+    SYS_pwritev2, SYS_statx, SYS_rseq, SYS_pidfd_send_signal, SYS_io_uring_setup,
+    SYS_io_uring_enter, SYS_io_uring_register, SYS_pidfd_open, SYS_clone3,
+    SYS_close_range, SYS_openat2, SYS_faccessat2,
+];
+
+/// syscalls known by name, as `SYS_` constant names
+#[cfg(target_arch = "aarch64")]
+const SYSCALLS: &[(&str, c_long)] = syscalls![
+    SYS_read, SYS_write, SYS_close, SYS_fstat, SYS_lseek, SYS_mmap, SYS_mprotect,
+    SYS_munmap, SYS_brk, SYS_rt_sigaction, SYS_rt_sigprocmask, SYS_rt_sigreturn, SYS_ioctl,
+    SYS_pread64, SYS_pwrite64, SYS_readv, SYS_writev, SYS_sched_yield, SYS_mremap,
+    SYS_msync, SYS_mincore, SYS_madvise, SYS_dup, SYS_nanosleep, SYS_getitimer,
+    SYS_setitimer, SYS_getpid, SYS_sendfile, SYS_socket, SYS_connect, SYS_accept,
+    SYS_sendto, SYS_recvfrom, SYS_sendmsg, SYS_recvmsg, SYS_shutdown, SYS_bind, SYS_listen,
+    SYS_getsockname, SYS_getpeername, SYS_socketpair, SYS_setsockopt, SYS_getsockopt,
+    SYS_clone, SYS_execve, SYS_exit, SYS_wait4, SYS_kill, SYS_uname, SYS_fcntl, SYS_flock,
+    SYS_fsync, SYS_fdatasync, SYS_truncate, SYS_ftruncate, SYS_getcwd, SYS_chdir,
+    SYS_fchdir, SYS_fchmod, SYS_fchown, SYS_umask, SYS_gettimeofday, SYS_getrlimit,
+    SYS_getrusage, SYS_sysinfo, SYS_times, SYS_ptrace, SYS_getuid, SYS_syslog, SYS_getgid,
+    SYS_setuid, SYS_setgid, SYS_geteuid, SYS_getegid, SYS_setpgid, SYS_getppid, SYS_setsid,
+    SYS_getgroups, SYS_setgroups, SYS_setresuid, SYS_getresuid, SYS_setresgid,
+    SYS_getresgid, SYS_getpgid, SYS_getsid, SYS_capget, SYS_capset, SYS_rt_sigpending,
+    SYS_rt_sigtimedwait, SYS_rt_sigsuspend, SYS_sigaltstack, SYS_statfs, SYS_fstatfs,
+    SYS_getpriority, SYS_setpriority, SYS_mlock, SYS_munlock, SYS_pivot_root, SYS_prctl,
+    SYS_setrlimit, SYS_chroot, SYS_sync, SYS_acct, SYS_mount, SYS_umount2, SYS_swapon,
+    SYS_swapoff, SYS_reboot, SYS_sethostname, SYS_init_module, SYS_delete_module,
+    SYS_quotactl, SYS_gettid, SYS_getxattr, SYS_fgetxattr, SYS_tkill, SYS_futex,
+    SYS_sched_setaffinity, SYS_sched_getaffinity, SYS_getdents64, SYS_set_tid_address,
+    SYS_restart_syscall, SYS_fadvise64, SYS_clock_gettime, SYS_clock_getres,
+    SYS_clock_nanosleep, SYS_exit_group, SYS_epoll_ctl, SYS_tgkill, SYS_waitid,
+    SYS_add_key, SYS_request_key, SYS_keyctl, SYS_inotify_add_watch, SYS_inotify_rm_watch,
+    SYS_openat, SYS_mkdirat, SYS_fchownat, SYS_newfstatat, SYS_unlinkat, SYS_linkat,
+    SYS_symlinkat, SYS_readlinkat, SYS_fchmodat, SYS_faccessat, SYS_pselect6, SYS_ppoll,
+    SYS_unshare, SYS_set_robust_list, SYS_get_robust_list, SYS_splice, SYS_tee,
+    SYS_vmsplice, SYS_utimensat, SYS_epoll_pwait, SYS_timerfd_create, SYS_fallocate,
+    SYS_timerfd_settime, SYS_timerfd_gettime, SYS_accept4, SYS_eventfd2, SYS_epoll_create1,
+    SYS_dup3, SYS_pipe2, SYS_inotify_init1, SYS_preadv, SYS_pwritev, SYS_perf_event_open,
+    SYS_recvmmsg, SYS_prlimit64, SYS_name_to_handle_at, SYS_open_by_handle_at,
+    SYS_sendmmsg, SYS_setns, SYS_getcpu, SYS_process_vm_readv, SYS_process_vm_writev,
+    SYS_finit_module, SYS_renameat2, SYS_seccomp, SYS_getrandom, SYS_memfd_create,
+    SYS_kexec_file_load, SYS_kexec_load, SYS_bpf, SYS_execveat, SYS_userfaultfd,
+    SYS_membarrier, SYS_mlock2, SYS_copy_file_range, SYS_preadv2, SYS_pwritev2, SYS_statx,
+    SYS_rseq, SYS_pidfd_send_signal, SYS_io_uring_setup, SYS_io_uring_enter,
+    SYS_io_uring_register, SYS_pidfd_open, SYS_clone3, SYS_close_range, SYS_openat2,
+    SYS_faccessat2,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `program` on the `struct seccomp_data` of a syscall,
+    /// failing on anything the filter is not expected to use
+    fn run(program: &[sock_filter], arch: u32, nr: c_long, arg0: u64) -> u32 {
+        let mut acc: u32 = 0;
+        let mut pc = 0;
+        loop {
+            let insn = program.get(pc).expect("jumped past the end of the program");
+            let code = insn.code as u32;
+            pc += 1;
+            match code {
+                c if c == libc::BPF_LD | libc::BPF_W | libc::BPF_ABS => {
+                    acc = match insn.k {
+                        SECCOMP_DATA_NR => nr as u32,
+                        SECCOMP_DATA_ARCH => arch,
+                        SECCOMP_DATA_ARG0 => arg0 as u32,
+                        k => panic!("[{}] unexpected seccomp_data offset", k),
+                    }
+                }
+                c if c == libc::BPF_RET => return insn.k,
+                c if c & 0x07 == libc::BPF_JMP => {
+                    let taken = match c & 0xf0 {
+                        libc::BPF_JEQ => acc == insn.k,
+                        libc::BPF_JGE => acc >= insn.k,
+                        BPF_JSET => acc & insn.k != 0,
+                        op => panic!("[{:#x}] unexpected jump", op),
+                    };
+                    pc += if taken { insn.jt } else { insn.jf } as usize;
+                }
+                c => panic!("[{:#x}] unexpected instruction", c),
+            }
+        }
+    }
+
+    fn nr(name: &str) -> c_long {
+        syscall_number(name).unwrap()
+    }
+
+    const EPERM: u32 = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+    #[test]
+    fn jumps_stay_in_bounds() {
+        let profiles = [
+            Profile::deny_list(),
+            Profile::allow_list(&["read"]).unwrap(),
+            Profile::allow_list(&[]).unwrap().with_action(Action::Kill),
+        ];
+        for profile in profiles.iter() {
+            for trap in [false, true].iter() {
+                let program = profile.program(*trap);
+                let jumps = program
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, insn)| insn.code as u32 & 0x07 == libc::BPF_JMP);
+                for (pc, insn) in jumps {
+                    let furthest = pc + 1 + insn.jt.max(insn.jf) as usize;
+                    assert!(furthest < program.len(), "[{}] jumps out", pc);
+                }
+                assert_eq!(program.last().unwrap().code as u32, libc::BPF_RET);
+            }
+        }
+    }
+
+    #[test]
+    fn deny_list() {
+        let program = Profile::deny_list().program(false);
+        assert_eq!(
+            run(&program, AUDIT_ARCH, nr("read"), 0),
+            libc::SECCOMP_RET_ALLOW
+        );
+        assert_eq!(run(&program, AUDIT_ARCH, nr("ptrace"), 0), EPERM);
+        assert_eq!(run(&program, AUDIT_ARCH, nr("userfaultfd"), 0), EPERM);
+        // the last syscall of the list falls through to the end
+        let last = DEFAULT_DENIED.last().unwrap();
+        assert_eq!(run(&program, AUDIT_ARCH, nr(last), 0), EPERM);
+    }
+
+    #[test]
+    fn allow_list() {
+        let program = Profile::allow_list(&["read"]).unwrap().program(false);
+        assert_eq!(
+            run(&program, AUDIT_ARCH, nr("read"), 0),
+            libc::SECCOMP_RET_ALLOW
+        );
+        assert_eq!(
+            run(&program, AUDIT_ARCH, nr("write"), 0),
+            libc::SECCOMP_RET_ALLOW
+        );
+        assert_eq!(run(&program, AUDIT_ARCH, nr("openat"), 0), EPERM);
+
+        let program = Profile::allow_list(&[])
+            .unwrap()
+            .with_action(Action::Kill)
+            .program(false);
+        let kill = libc::SECCOMP_RET_KILL_PROCESS;
+        assert_eq!(run(&program, AUDIT_ARCH, nr("openat"), 0), kill);
+        assert_eq!(Profile::allow_list(&["read", "nope"]), Err("nope"));
+    }
+
+    #[test]
+    fn trapped_errno() {
+        let program = Profile::deny_list().program(true);
+        let trap = libc::SECCOMP_RET_TRAP | libc::EPERM as u32;
+        assert_eq!(run(&program, AUDIT_ARCH, nr("ptrace"), 0), trap);
+        assert_eq!(
+            run(&program, AUDIT_ARCH, nr("read"), 0),
+            libc::SECCOMP_RET_ALLOW
+        );
+    }
+
+    #[test]
+    fn foreign_architecture() {
+        let program = Profile::deny_list().program(false);
+        let kill = libc::SECCOMP_RET_KILL_PROCESS;
+        assert_eq!(run(&program, AUDIT_ARCH ^ 1, nr("read"), 0), kill);
+        if cfg!(target_arch = "x86_64") {
+            let x32 = (X32_SYSCALL_BIT as c_long) | nr("read");
+            assert_eq!(run(&program, AUDIT_ARCH, x32, 0), EPERM);
+        }
+    }
+
+    #[test]
+    fn clones() {
+        let enosys = libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32;
+        for program in [
+            Profile::deny_list().program(false),
+            Profile::allow_list(&["clone"]).unwrap().program(false),
+        ]
+        .iter()
+        {
+            assert_eq!(run(program, AUDIT_ARCH, nr("clone3"), 0), enosys);
+            let thread = (libc::CLONE_VM | libc::CLONE_THREAD) as u64;
+            assert_eq!(
+                run(program, AUDIT_ARCH, nr("clone"), thread),
+                libc::SECCOMP_RET_ALLOW
+            );
+            let netns = libc::CLONE_NEWNET as u64 | libc::SIGCHLD as u64;
+            assert_eq!(run(program, AUDIT_ARCH, nr("clone"), netns), EPERM);
+            // the upper half of the flags is not looked at
+            assert_eq!(
+                run(program, AUDIT_ARCH, nr("clone"), 1 << 32),
+                libc::SECCOMP_RET_ALLOW
+            );
+        }
+        // clone is not in the allow list, namespaces or not
+        let program = Profile::allow_list(&[]).unwrap().program(false);
+        assert_eq!(run(&program, AUDIT_ARCH, nr("clone"), 0), EPERM);
+    }
+
+    #[test]
+    fn syscall_names() {
+        assert_eq!(syscall_name(nr("ptrace")), Some("ptrace"));
+        assert_eq!(syscall_number("not_a_syscall"), None);
+        for name in DEFAULT_DENIED.iter().chain(BASELINE) {
+            if let Some(nr) = syscall_number(name) {
+                assert_eq!(syscall_name(nr), Some(*name));
+            }
+        }
+        for name in BASELINE {
+            assert!(syscall_number(name).is_some(), "{}", name);
+        }
+    }
+}
@@ -1,4 +1,7 @@
 use nix::sys::signal::{self, sigaction, SaFlags, SigAction, SigHandler, SigSet};
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::time::Duration;
 
 /// Block signals for calling thread
 /// Change the signal mask of the calling thread through `sigprocmask(2)`.
@@ -63,6 +66,53 @@ pub fn set_sa_nocldstop() -> Result<(), nix::Error> {
     }
 }
 
+/// Restore the default disposition of signals,
+/// e.g. to drop handlers inherited from the parent in a cloned process
+pub fn reset_default(set: &[signal::Signal]) -> Result<(), nix::Error> {
+    let sigact = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+    for sig in set {
+        unsafe { sigaction(*sig, &sigact) }?;
+    }
+    Ok(())
+}
+
+/// Send `sig` to the process referred to by `pidfd`.
+/// Unlike `kill` this can never hit another process that reused the pid.
+pub fn pidfd_send_signal(pidfd: RawFd, sig: signal::Signal) -> Result<(), nix::Error> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            pidfd,
+            sig as libc::c_int,
+            ptr::null::<libc::siginfo_t>(),
+            0,
+        )
+    };
+    match ret {
+        -1 => Err(nix::Error::last()),
+        _ => Ok(()),
+    }
+}
+
+/// Have SIGALRM delivered to the calling process once `after` has elapsed,
+/// replacing any pending alarm. A zero duration cancels the alarm.
+pub fn set_alarm(after: Duration) -> Result<(), nix::Error> {
+    let timer = libc::itimerval {
+        it_interval: libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        },
+        it_value: libc::timeval {
+            tv_sec: after.as_secs() as libc::time_t,
+            tv_usec: after.subsec_micros() as libc::suseconds_t,
+        },
+    };
+    match unsafe { libc::setitimer(libc::ITIMER_REAL, &timer, ptr::null_mut()) } {
+        -1 => Err(nix::Error::last()),
+        _ => Ok(()),
+    }
+}
+
 extern "C" fn empty(_: libc::c_int) {}
 
 pub fn default_sigcont() -> Result<(), nix::Error> {
@@ -75,6 +125,15 @@ pub fn default_sigcont() -> Result<(), nix::Error> {
     }
 }
 
+/// wait for one of the signals in `set`, which must be blocked
+pub fn sigwait(set: &[signal::Signal]) -> Result<signal::Signal, nix::Error> {
+    let mut sigset = SigSet::empty();
+    for sig in set {
+        sigset.add(*sig);
+    }
+    sigset.wait()
+}
+
 pub fn sigsuspend() {
     unsafe { libc::sigsuspend(SigSet::empty().as_ref()) };
 }
@@ -0,0 +1,289 @@
+use crate::request::{HttpRequestMethod, PotatoRequest};
+use crate::response;
+use libpotato::cgroup::Usage;
+use serde::Deserialize;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Line format of the access log
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Common Log Format followed by the duration in microseconds,
+    /// the sandbox as `dir/pid` and its usage as
+    /// `cpu_us/memory_peak/io_read/io_write`, each `-` when not known
+    Common,
+    /// Combined Log Format, i.e. Common with referer and user agent,
+    /// followed by the same fields as `Common`
+    #[default]
+    Combined,
+    /// one JSON object per line
+    Json,
+}
+
+/// One served request
+pub struct LogEntry {
+    /// `None` for clients connected over a Unix domain socket
+    pub client: Option<SocketAddr>,
+    /// when the request was received
+    pub time: SystemTime,
+    pub method: HttpRequestMethod,
+    pub path: String,
+    /// path of the matched route, see `PotatoRequest::route`
+    pub route: Option<String>,
+    pub target: String,
+    pub version: String,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub status: u16,
+    /// bytes of the response sent, headers included
+    pub bytes: usize,
+    pub duration: Duration,
+    /// number of the sandbox directory and PID of its init process
+    pub sandbox: Option<(usize, i32)>,
+    /// resources used by the sandbox, when accounted
+    pub usage: Option<Usage>,
+    started: Instant,
+}
+
+impl LogEntry {
+    /// Entry for `req`, the duration is counted from now
+    pub fn new(client: Option<SocketAddr>, req: &PotatoRequest) -> LogEntry {
+        LogEntry {
+            client,
+            time: SystemTime::now(),
+            method: req.method,
+            path: req.path.clone(),
+            route: req.route.clone(),
+            target: req.target.clone(),
+            version: req.version.clone(),
+            referer: req.headers.get("Referer").map(str::to_string),
+            user_agent: req.headers.get("User-Agent").map(str::to_string),
+            status: 0,
+            bytes: 0,
+            duration: Duration::ZERO,
+            sandbox: None,
+            usage: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Record the response sent back
+    pub fn finish(self, status: u16, bytes: usize) -> LogEntry {
+        self.finish_at(status, bytes, Instant::now())
+    }
+
+    /// Record the response sent back, done sending at `done`
+    pub fn finish_at(mut self, status: u16, bytes: usize, done: Instant) -> LogEntry {
+        self.status = status;
+        self.bytes = bytes;
+        self.duration = done.saturating_duration_since(self.started);
+        self
+    }
+
+    pub fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Common => format!("{} {}", self.common(), self.extra()),
+            LogFormat::Combined => format!(
+                "{} \"{}\" \"{}\" {}",
+                self.common(),
+                clf_escape(self.referer.as_deref().unwrap_or("-")),
+                clf_escape(self.user_agent.as_deref().unwrap_or("-")),
+                self.extra()
+            ),
+            LogFormat::Json => self.json(),
+        }
+    }
+
+    fn client_ip(&self) -> String {
+        match self.client {
+            Some(addr) => addr.ip().to_string(),
+            None => "-".to_string(),
+        }
+    }
+
+    fn common(&self) -> String {
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {}",
+            self.client_ip(),
+            clf_time(self.time),
+            self.method,
+            clf_escape(&self.target),
+            self.version,
+            self.status,
+            bytes
+        )
+    }
+
+    fn extra(&self) -> String {
+        let sandbox = match self.sandbox {
+            Some((dir, pid)) => format!("{}/{}", dir, pid),
+            None => "-".to_string(),
+        };
+        let usage = match &self.usage {
+            Some(usage) => {
+                let bytes = |n: Option<u64>| n.map_or("-".to_string(), |n| n.to_string());
+                format!(
+                    "{}/{}/{}/{}",
+                    usage.cpu.as_micros(),
+                    bytes(usage.memory_peak),
+                    bytes(usage.io_read),
+                    bytes(usage.io_write)
+                )
+            }
+            None => "-".to_string(),
+        };
+        format!("{} {} {}", self.duration.as_micros(), sandbox, usage)
+    }
+
+    fn json(&self) -> String {
+        let mut line = String::from("{");
+        let secs = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let _ = write!(
+            line,
+            "\"time\":{}.{:03}",
+            secs.as_secs(),
+            secs.subsec_millis()
+        );
+        let _ = write!(
+            line,
+            ",\"client\":{}",
+            json_opt(self.client.map(|c| c.ip().to_string()))
+        );
+        let _ = write!(line, ",\"method\":\"{}\"", self.method);
+        let _ = write!(line, ",\"path\":{}", json_string(&self.path));
+        let _ = write!(line, ",\"route\":{}", json_opt(self.route.clone()));
+        let _ = write!(line, ",\"target\":{}", json_string(&self.target));
+        let _ = write!(line, ",\"version\":{}", json_string(&self.version));
+        let _ = write!(line, ",\"status\":{}", self.status);
+        let _ = write!(line, ",\"bytes\":{}", self.bytes);
+        let _ = write!(line, ",\"duration_us\":{}", self.duration.as_micros());
+        let _ = write!(line, ",\"referer\":{}", json_opt(self.referer.clone()));
+        let _ = write!(
+            line,
+            ",\"user_agent\":{}",
+            json_opt(self.user_agent.clone())
+        );
+        if let Some((dir, pid)) = self.sandbox {
+            let _ = write!(line, ",\"sandbox_dir\":{},\"sandbox_pid\":{}", dir, pid);
+        }
+        if let Some(usage) = &self.usage {
+            let number = |n: Option<u64>| n.map_or("null".to_string(), |n| n.to_string());
+            let _ = write!(line, ",\"cpu_us\":{}", usage.cpu.as_micros());
+            let _ = write!(line, ",\"memory_peak\":{}", number(usage.memory_peak));
+            let _ = write!(line, ",\"io_read_bytes\":{}", number(usage.io_read));
+            let _ = write!(line, ",\"io_write_bytes\":{}", number(usage.io_write));
+        }
+        line.push('}');
+        line
+    }
+}
+
+/// Access log file, reopened with `reopen` once rotated away
+pub struct AccessLog {
+    path: String,
+    format: LogFormat,
+    file: Mutex<File>,
+}
+
+impl AccessLog {
+    /// Append to the file at `path`, creating it if needed
+    pub fn open(path: &str, format: LogFormat) -> io::Result<AccessLog> {
+        Ok(AccessLog {
+            path: path.to_string(),
+            format,
+            file: Mutex::new(open_append(path)?),
+        })
+    }
+
+    /// Switch to a fresh file at the same path, e.g. after logrotate moved it
+    pub fn reopen(&self) -> io::Result<()> {
+        let file = open_append(&self.path)?;
+        *self.file.lock().unwrap() = file;
+        Ok(())
+    }
+
+    /// Append `entry` to the log, a failure to write is only reported on stderr
+    pub fn log(&self, entry: &LogEntry) {
+        let mut line = entry.format(self.format);
+        line.push('\n');
+        // a single write keeps lines from concurrent requests apart
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            eprintln!("[{}] failed writing access log: {}", self.path, e);
+        }
+    }
+}
+
+fn open_append(path: &str) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// `[10/Oct/2000:13:55:36 +0000]` without the brackets
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (year, month, day) = response::civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Escape quotes, backslashes and control characters the way Apache does
+fn clf_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_opt(s: Option<String>) -> String {
+    match s {
+        Some(s) => json_string(&s),
+        None => "null".to_string(),
+    }
+}
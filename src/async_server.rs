@@ -0,0 +1,499 @@
+use crate::access_log::LogEntry;
+use crate::isolation::{isolate_req, IsolationSetting, Sandbox};
+use crate::listener::{self, Listener};
+use crate::prep;
+use crate::request::{HttpRequestMethod, PotatoRequest, ReadDeadline, RequestError, RequestParser};
+use crate::response::PotatoResponse;
+use crate::server::{
+    error_response, handle_signals, log_unanswered_pipelined, print_banner, Endpoint,
+    PotatoRequestHandler, PotatoServer,
+};
+use crate::status::StatusCode;
+use crate::tls::Terminator;
+use libpotato::nix::sys::wait::waitpid;
+use libpotato::nix::unistd::{self, Pid};
+use std::fs;
+use std::io::{self, prelude::*};
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Interest, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::runtime::Runtime;
+use tokio::{task, time};
+
+/// How often the accept loops check whether the server is closing
+const CLOSING_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Connection served by the async server
+enum AsyncStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    /// TLS terminated by the terminator process of the server, the
+    /// socket carries the decrypted stream of the client at the address
+    Tls(UnixStream, Option<SocketAddr>),
+}
+
+impl AsyncStream {
+    /// Address of the client, `None` over a Unix domain socket
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            AsyncStream::Tcp(stream) => stream.peer_addr().ok(),
+            AsyncStream::Unix(_) => None,
+            AsyncStream::Tls(_, addr) => *addr,
+        }
+    }
+}
+
+impl AsyncRead for AsyncStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            AsyncStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            AsyncStream::Tls(stream, _) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for AsyncStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AsyncStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            AsyncStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            AsyncStream::Tls(stream, _) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            AsyncStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            AsyncStream::Tls(stream, _) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            AsyncStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            AsyncStream::Tls(stream, _) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+impl PotatoServer {
+    /// Serve requests on a tokio runtime instead of a thread per connection.
+    ///
+    /// Async handlers run on the runtime, blocking ones on its blocking pool.
+    /// Isolated requests are cloned from the blocking pool as well and their
+    /// sandbox is then awaited through the pidfd of its init process, so
+    /// SIGCHLD is left alone.
+    ///
+    /// Signals are handled as by `start`: SIGTERM and SIGINT drain the
+    /// server before returning, SIGHUP reloads it and SIGUSR2 reopens the
+    /// access log.
+    pub fn start_async(mut self) {
+        let listeners = self.bind_listeners();
+        self.start_terminator();
+        // prep_host runs its own runtime, it can't be nested in ours
+        self.prep_host();
+        print_banner(&listeners);
+
+        let inflight = Arc::clone(&self.inflight);
+        let reaper = Arc::clone(&self.inflight);
+        thread::spawn(move || reaper.reap());
+        let shutdown_timeout = self.shutdown_timeout;
+        let protected_runtime_dir = Arc::new(Mutex::new(self.runtime_dir.clone()));
+        let current = Arc::new(RwLock::new(Arc::new(self)));
+        // the accept loops check for shutdown on their own, a Unix listener
+        // shut down under them would keep a non-blocking accept spinning
+        handle_signals(Arc::clone(&current), Vec::new(), true);
+
+        let runtime = Runtime::new().expect("Failed to start tokio runtime");
+        runtime.block_on(accept_async(listeners, current, protected_runtime_dir));
+
+        // connections are still served by the runtime while draining
+        println!("Shutting down, waiting for in-flight requests");
+        inflight.drain(Instant::now() + shutdown_timeout);
+    }
+
+    /// Async counterpart of `handle_connection`. A request routed to an
+    /// isolated handler hands the connection over to its sandbox.
+    async fn handle_connection_async(
+        &self,
+        mut stream: AsyncStream,
+        protected_runtime_dir: Arc<Mutex<String>>,
+    ) {
+        let client = stream.peer_addr();
+        let mut parser = RequestParser::new(self.limits);
+        let mut served: usize = 0;
+
+        loop {
+            let mut req = match self.read_request_async(&mut stream, &mut parser).await {
+                Ok(req) => req,
+                Err(e) => {
+                    if let Some(status) = e.status() {
+                        let pres = error_response(status, &e.to_string());
+                        let _ = self
+                            .write_response_async(&mut stream, pres, HttpRequestMethod::GET)
+                            .await;
+                    }
+                    break;
+                }
+            };
+            served += 1;
+            let keep_alive = req.keep_alive()
+                && served < self.max_keep_alive_requests
+                && !self.inflight.is_closing();
+            let method = req.method;
+            let routed = self.find_handler(&mut req);
+            let entry = self.log_entry(client, &req);
+
+            let pres = match routed {
+                Ok((Endpoint::Async(handler), _)) => {
+                    // a panicking handler only takes its own task down
+                    let handle = task::spawn(handler.handle(req));
+                    let abort = handle.abort_handle();
+                    let pres = self.with_handler_timeout(handle).await;
+                    // cancels a handler that timed out, like dropping it would
+                    abort.abort();
+                    pres
+                }
+                Ok((Endpoint::Blocking(handler), Some(isolation_setting))) if self.isolation => {
+                    // the sandbox answers with `Connection: close`
+                    if !parser.is_empty() {
+                        log_unanswered_pipelined(client);
+                    }
+                    req.state = self.state.snapshot();
+                    let isolation_setting = self.sandbox_setting(isolation_setting);
+                    let rootfs = Arc::clone(&protected_runtime_dir);
+                    return self
+                        .isolate_async(stream, req, handler, isolation_setting, rootfs, entry)
+                        .await;
+                }
+                Ok((Endpoint::Blocking(handler), _)) => {
+                    self.with_handler_timeout(task::spawn_blocking(move || handler.handle(req)))
+                        .await
+                }
+                Err(pres) => pres,
+            };
+            let keep_alive = keep_alive && !pres.closes_connection();
+            let connection = if keep_alive { "keep-alive" } else { "close" };
+            let pres = pres.set_header("Connection", connection);
+            let status = pres.status().as_u16();
+
+            let written = self.write_response_async(&mut stream, pres, method).await;
+            self.log_access(entry, status, written.as_ref().map_or(0, |n| *n));
+            if written.is_err() || !keep_alive {
+                break;
+            }
+        }
+        let _ = stream.shutdown().await;
+    }
+
+    /// Run `req` in a sandbox answering on `stream`, then wait for the sandbox to be torn down.
+    async fn isolate_async(
+        &self,
+        stream: AsyncStream,
+        req: PotatoRequest,
+        handler: PotatoRequestHandler,
+        isolation_setting: IsolationSetting,
+        protected_runtime_dir: Arc<Mutex<String>>,
+        entry: Option<LogEntry>,
+    ) {
+        let sandbox = match stream {
+            AsyncStream::Tcp(stream) => {
+                // the sandbox writes its response with blocking io
+                let stream = match stream.into_std() {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                if stream
+                    .set_nonblocking(false)
+                    .and_then(|_| stream.set_write_timeout(Some(self.write_timeout)))
+                    .is_err()
+                {
+                    return;
+                }
+                spawn_sandbox(
+                    stream,
+                    req,
+                    handler,
+                    isolation_setting,
+                    protected_runtime_dir,
+                )
+                .await
+            }
+            AsyncStream::Unix(stream) | AsyncStream::Tls(stream, _) => {
+                let stream = match stream.into_std() {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                if stream
+                    .set_nonblocking(false)
+                    .and_then(|_| stream.set_write_timeout(Some(self.write_timeout)))
+                    .is_err()
+                {
+                    return;
+                }
+                spawn_sandbox(
+                    stream,
+                    req,
+                    handler,
+                    isolation_setting,
+                    protected_runtime_dir,
+                )
+                .await
+            }
+        };
+
+        let sandbox = match sandbox {
+            Some(sandbox) => sandbox,
+            None => {
+                self.clone_failed();
+                return self.log_access(entry, 500, 0);
+            }
+        };
+        let pid = Pid::from_raw(sandbox.pid());
+        // the sandbox itself is tracked, so that draining can kill it
+        let pidfd = unistd::dup(sandbox.as_raw_fd()).map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+        let (info, report) = self.track(sandbox);
+        let waited = match pidfd {
+            Ok(pidfd) => wait_sandbox(pid, pidfd).await,
+            Err(e) => Err(io::Error::other(e)),
+        };
+        if let Err(e) = waited {
+            eprintln!("Failed waiting for sandbox: {}", e);
+        }
+        self.log_sandbox(entry, info, report);
+    }
+
+    /// Wait for the response of a handler, `504` once the handler timeout
+    /// expired. A blocking handler is left running on the blocking pool.
+    async fn with_handler_timeout<F, E>(&self, handler: F) -> PotatoResponse
+    where
+        F: std::future::Future<Output = Result<PotatoResponse, E>>,
+    {
+        let pres = match self.handler_timeout {
+            Some(timeout) => match time::timeout(timeout, handler).await {
+                Ok(pres) => pres,
+                Err(_) => return error_response(StatusCode::GATEWAY_TIMEOUT, "Handler timed out"),
+            },
+            None => handler.await,
+        };
+        pres.unwrap_or_else(|_| {
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "Handler failure")
+        })
+    }
+
+    /// Write a response, returning the number of bytes written. Gives up once the write timeout expired
+    async fn write_response_async(
+        &self,
+        stream: &mut AsyncStream,
+        response: PotatoResponse,
+        method: HttpRequestMethod,
+    ) -> io::Result<usize> {
+        let res = response.to_http_response(method);
+        let write = async {
+            stream.write_all(&res).await?;
+            stream.flush().await?;
+            Ok(res.len())
+        };
+        match time::timeout(self.write_timeout, write).await {
+            Ok(written) => written,
+            Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut)),
+        }
+    }
+
+    /// Async counterpart of `RequestReader::read_request_timed`
+    async fn read_request_async(
+        &self,
+        stream: &mut AsyncStream,
+        parser: &mut RequestParser,
+    ) -> Result<PotatoRequest, RequestError> {
+        let mut deadline = ReadDeadline::new(self.read_timeouts());
+        let mut buffer = [0; 4096];
+        loop {
+            if let Some(req) = parser.parse()? {
+                return Ok(req);
+            }
+
+            let timeout = deadline.remaining(parser.stage())?;
+            let n = match time::timeout(timeout, stream.read(&mut buffer)).await {
+                Ok(read) => read?,
+                // the deadline tells whether it is really over
+                Err(_) => continue,
+            };
+            if n == 0 {
+                if parser.is_empty() {
+                    return Err(RequestError::ConnectionClosed);
+                }
+                return Err(RequestError::BadRequest("connection closed mid-request"));
+            }
+            parser.feed(&buffer[..n]);
+        }
+    }
+}
+
+/// Run one accept loop per listener until all of them ended on shutdown
+async fn accept_async(
+    listeners: Vec<Listener>,
+    current: Arc<RwLock<Arc<PotatoServer>>>,
+    protected_runtime_dir: Arc<Mutex<String>>,
+) {
+    let mut accept_loops = task::JoinSet::new();
+    for listener in listeners {
+        let current = Arc::clone(&current);
+        let protected_runtime_dir = Arc::clone(&protected_runtime_dir);
+        accept_loops.spawn(accept_loop_async(listener, current, protected_runtime_dir));
+    }
+    while accept_loops.join_next().await.is_some() {}
+}
+
+/// Serve every connection accepted on `listener` in its own task,
+/// with the server config current when it was accepted
+async fn accept_loop_async(
+    listener: Listener,
+    current: Arc<RwLock<Arc<PotatoServer>>>,
+    protected_runtime_dir: Arc<Mutex<String>>,
+) {
+    enum AsyncListener {
+        Tcp(TcpListener),
+        Unix(UnixListener),
+    }
+    // registered on a dup so that `listener` still removes its socket file on drop
+    let async_listener = match &listener {
+        Listener::Tcp(listener) => listener.try_clone().and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener).map(AsyncListener::Tcp)
+        }),
+        Listener::Unix(listener, _) => listener.try_clone().and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            UnixListener::from_std(listener).map(AsyncListener::Unix)
+        }),
+    }
+    .unwrap();
+
+    loop {
+        let accept = async {
+            match &async_listener {
+                AsyncListener::Tcp(listener) => listener
+                    .accept()
+                    .await
+                    .map(|(stream, _)| AsyncStream::Tcp(stream)),
+                AsyncListener::Unix(listener) => listener
+                    .accept()
+                    .await
+                    .map(|(stream, _)| AsyncStream::Unix(stream)),
+            }
+        };
+        // wakes up every now and then to check for shutdown
+        let stream = time::timeout(CLOSING_POLL_INTERVAL, accept).await;
+        let server = Arc::clone(&current.read().unwrap());
+        if server.inflight.is_closing() {
+            return;
+        }
+        let stream = match stream {
+            Ok(Ok(stream)) => stream,
+            Err(_) => continue,
+            Ok(Err(e)) => {
+                if !listener::is_connection_error(&e) {
+                    eprintln!("Failed accepting on {}: {}", listener, e);
+                    time::sleep(listener::ACCEPT_BACKOFF).await;
+                }
+                continue;
+            }
+        };
+        let guard = server.inflight.enter();
+        let arc_runtime_dir = Arc::clone(&protected_runtime_dir);
+        tokio::spawn(async move {
+            let _guard = guard;
+            let stream = match (server.tls_terminator(), stream) {
+                (Some(terminator), AsyncStream::Tcp(stream)) => {
+                    match terminate_async(terminator, stream, server.write_timeout) {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    }
+                }
+                (_, stream) => stream,
+            };
+            server
+                .handle_connection_async(stream, arc_runtime_dir)
+                .await
+        });
+    }
+}
+
+/// Hand the TLS connection `stream` to `terminator`,
+/// the handshake happens on the first read of the returned stream
+fn terminate_async(
+    terminator: &Terminator,
+    stream: TcpStream,
+    write_timeout: Duration,
+) -> io::Result<AsyncStream> {
+    let client = stream.peer_addr().ok();
+    let stream = stream.into_std()?;
+    // kept by the terminator for the encrypted stream
+    stream.set_write_timeout(Some(write_timeout))?;
+    let stream = terminator.terminate(stream)?;
+    stream.set_nonblocking(true)?;
+    Ok(AsyncStream::Tls(UnixStream::from_std(stream)?, client))
+}
+
+/// Prepare a rootfs and clone the sandbox from the blocking pool,
+/// answering with an error on `stream` when the sandbox can't be created
+async fn spawn_sandbox<S>(
+    stream: S,
+    req: PotatoRequest,
+    handler: PotatoRequestHandler,
+    mut isolation_setting: IsolationSetting,
+    protected_runtime_dir: Arc<Mutex<String>>,
+) -> Option<Sandbox>
+where
+    S: AsRawFd + Write + Send + 'static,
+{
+    task::spawn_blocking(move || {
+        let runtime_dir = protected_runtime_dir.lock().unwrap();
+        isolation_setting.rootfs_path = prep::fs_prep(&runtime_dir);
+        std::mem::drop(runtime_dir); // unlock mutex
+
+        let rootfs = isolation_setting.rootfs_path.clone();
+        match isolate_req(stream, req, handler, isolation_setting) {
+            Ok(sandbox) => Some(sandbox),
+            Err(mut stream) => {
+                let _ = fs::remove_dir_all(&rootfs);
+                let pres = error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Isolation failure: clone init",
+                );
+                let _ = stream.write_all(&pres.to_http_response(HttpRequestMethod::GET));
+                None
+            }
+        }
+    })
+    .await
+    .unwrap_or(None)
+}
+
+/// Wait until the init process `pid` of a sandbox exits and reap it,
+/// `pidfd` refers to it
+async fn wait_sandbox(pid: Pid, pidfd: OwnedFd) -> io::Result<()> {
+    let pidfd = unsafe { AsyncFd::register_with_interest(pidfd, Interest::READABLE)? };
+    // a pidfd turns readable once the process has exited
+    let _ = pidfd.readable().await?;
+    waitpid(pid, None).map_err(io::Error::other)?;
+    Ok(())
+}
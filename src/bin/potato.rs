@@ -2,28 +2,84 @@ use lazy_static::lazy_static;
 use libpotato::nix;
 use nix::unistd;
 
+use potato_ws::config::Config;
 use potato_ws::isolation::IsolationSetting;
 use potato_ws::request::{HttpRequestMethod::*, PotatoRequest};
 use potato_ws::response::PotatoResponse;
-use potato_ws::server::PotatoServer;
+use potato_ws::server::{PotatoRequestHandler, PotatoServer};
+use potato_ws::status::StatusCode;
 
+use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::process;
+use std::sync::Arc;
 
 lazy_static! {
     static ref RUNTIME_DIR: String = {
         let uid = unistd::getuid();
         format!("/var/run/user/{}/potato", uid)
     };
-    static ref STATIC_DIR: String = {
-        let static_dir = env::var("STATIC_DIR").unwrap_or("STATIC_DIR not found".to_string());
-        format!("{}", static_dir)
-    };
+    static ref STATIC_DIR: String =
+        env::var("STATIC_DIR").unwrap_or_else(|_| "STATIC_DIR not found".to_string());
 }
 
+const USAGE: &str = "usage: potato [--config <file> [--check]]";
+
 fn main() {
+    let mut args = env::args().skip(1);
+    match args.next().as_deref() {
+        None => start_default(),
+        Some("--config") => {
+            let path = args.next().unwrap_or_else(|| usage());
+            let check = match args.next().as_deref() {
+                None => false,
+                Some("--check") => true,
+                Some(_) => usage(),
+            };
+            start_from_config(&path, check)
+        }
+        Some(_) => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2)
+}
+
+/// Handlers routes of a configuration file can refer to
+fn handlers() -> HashMap<&'static str, PotatoRequestHandler> {
+    let mut handlers: HashMap<&str, PotatoRequestHandler> = HashMap::new();
+    handlers.insert("hello", Arc::new(hello));
+    handlers.insert("hi", Arc::new(hi));
+    handlers.insert("hanoi", Arc::new(hanoi));
+    handlers.insert("add", Arc::new(simple_add));
+    handlers.insert("sort", Arc::new(bubble_sort));
+    handlers.insert("serve_file", Arc::new(serve_file));
+    handlers
+}
+
+/// Start the server described in `path`, or only validate it with `check`
+fn start_from_config(path: &str, check: bool) {
+    let handlers = handlers();
+    let config = Config::from_file(path).and_then(|config| match check {
+        true => config.validate(&handlers).map(|_| None),
+        false => config.into_server(&handlers).map(Some),
+    });
+    match config {
+        Ok(Some(server)) => server.start(),
+        Ok(None) => println!("[{}] configuration is valid", path),
+        Err(e) => {
+            eprintln!("[{}] invalid configuration:\n{}", path, e);
+            process::exit(1)
+        }
+    }
+}
+
+fn start_default() {
     let isolation = IsolationSetting::new().add_bind_mount_point(&STATIC_DIR, "resources");
 
     let potato_server = PotatoServer::new("8000", &RUNTIME_DIR, true);
@@ -40,13 +96,13 @@ fn main() {
 fn hello(_: PotatoRequest) -> PotatoResponse {
     let res = PotatoResponse::new();
     let body = "Hello World!".as_bytes();
-    res.set_status("200 OK").add_body(body.to_owned())
+    res.set_status(StatusCode::OK).add_body(body.to_owned())
 }
 
 fn hi(_: PotatoRequest) -> PotatoResponse {
     let res = PotatoResponse::new();
     let body = "Hi World".as_bytes();
-    res.set_status("200 OK").add_body(body.to_owned())
+    res.set_status(StatusCode::OK).add_body(body.to_owned())
 }
 
 fn simple_add(req: PotatoRequest) -> PotatoResponse {
@@ -64,12 +120,13 @@ fn simple_add(req: PotatoRequest) -> PotatoResponse {
             }
 
             let body = result.to_string().as_bytes().to_owned();
-            res.set_status("200 OK").add_body(body)
+            res.set_status(StatusCode::OK).add_body(body)
         }
-        None => res.set_status("400 Bad Request"),
+        None => res.set_status(StatusCode::BAD_REQUEST),
     }
 }
 
+#[allow(clippy::only_used_in_recursion)]
 fn compute_hanoi(num: i32, from: i32, to: i32, via: i32) {
     if num > 0 {
         compute_hanoi(num - 1, from, via, to);
@@ -88,9 +145,9 @@ fn hanoi(req: PotatoRequest) -> PotatoResponse {
 
             let result = "Success";
             let body = result.to_string().as_bytes().to_owned();
-            res.set_status("200 OK").add_body(body)
+            res.set_status(StatusCode::OK).add_body(body)
         }
-        None => res.set_status("400 Bad Request"),
+        None => res.set_status(StatusCode::BAD_REQUEST),
     }
 }
 
@@ -119,13 +176,13 @@ fn bubble_sort(req: PotatoRequest) -> PotatoResponse {
             let mut result = String::new();
             for k in vec {
                 result.push_str(&k.to_string());
-                result = result + " ";
+                result += " ";
             }
 
             let body = result.to_string().as_bytes().to_owned();
-            res.set_status("200 OK").add_body(body)
+            res.set_status(StatusCode::OK).add_body(body)
         }
-        None => res.set_status("400 Bad Request"),
+        None => res.set_status(StatusCode::BAD_REQUEST),
     }
 }
 
@@ -140,11 +197,11 @@ fn serve_file(req: PotatoRequest) -> PotatoResponse {
         // read the whole file
         match file.read_to_string(&mut contents) {
             Ok(_) => res
-                .set_status("200 OK")
+                .set_status(StatusCode::OK)
                 .add_body(contents.as_bytes().to_owned()),
-            Err(_) => res.set_status("500 Internal Server Error"),
+            Err(_) => res.set_status(StatusCode::INTERNAL_SERVER_ERROR),
         }
     } else {
-        res.set_status("404 Not Found")
+        res.set_status(StatusCode::NOT_FOUND)
     }
 }
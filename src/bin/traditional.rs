@@ -1,26 +1,82 @@
 use lazy_static::lazy_static;
 use libpotato::nix;
 
+use potato_ws::config::Config;
 use potato_ws::request::{HttpRequestMethod::*, PotatoRequest};
 use potato_ws::response::PotatoResponse;
-use potato_ws::server::PotatoServer;
+use potato_ws::server::{PotatoRequestHandler, PotatoServer};
+use potato_ws::status::StatusCode;
 
+use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::io::Read;
+use std::process;
+use std::sync::Arc;
 
 lazy_static! {
     static ref RUNTIME_DIR: String = {
         let uid = nix::unistd::getuid();
         format!("/var/run/user/{}/potato", uid)
     };
-    static ref STATIC_DIR: String = {
-        let static_dir = env::var("STATIC_DIR").unwrap_or("STATIC_DIR not found".to_string());
-        format!("{}", static_dir)
-    };
+    static ref STATIC_DIR: String =
+        env::var("STATIC_DIR").unwrap_or_else(|_| "STATIC_DIR not found".to_string());
 }
 
+const USAGE: &str = "usage: traditional [--config <file> [--check]]";
+
 fn main() {
+    let mut args = env::args().skip(1);
+    match args.next().as_deref() {
+        None => start_default(),
+        Some("--config") => {
+            let path = args.next().unwrap_or_else(|| usage());
+            let check = match args.next().as_deref() {
+                None => false,
+                Some("--check") => true,
+                Some(_) => usage(),
+            };
+            start_from_config(&path, check)
+        }
+        Some(_) => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2)
+}
+
+/// Handlers routes of a configuration file can refer to
+fn handlers() -> HashMap<&'static str, PotatoRequestHandler> {
+    let mut handlers: HashMap<&str, PotatoRequestHandler> = HashMap::new();
+    handlers.insert("hello", Arc::new(hello));
+    handlers.insert("hi", Arc::new(hi));
+    handlers.insert("hanoi", Arc::new(hanoi));
+    handlers.insert("add", Arc::new(simple_add));
+    handlers.insert("sort", Arc::new(bubble_sort));
+    handlers.insert("serve_file", Arc::new(serve_file));
+    handlers
+}
+
+/// Start the server described in `path`, or only validate it with `check`
+fn start_from_config(path: &str, check: bool) {
+    let handlers = handlers();
+    let config = Config::from_file(path).and_then(|config| match check {
+        true => config.validate(&handlers).map(|_| None),
+        false => config.into_server(&handlers).map(Some),
+    });
+    match config {
+        Ok(Some(server)) => server.start(),
+        Ok(None) => println!("[{}] configuration is valid", path),
+        Err(e) => {
+            eprintln!("[{}] invalid configuration:\n{}", path, e);
+            process::exit(1)
+        }
+    }
+}
+
+fn start_default() {
     let potato_server = PotatoServer::new("8000", &RUNTIME_DIR, false);
     potato_server
         .add_default_handler(serve_file)
@@ -35,17 +91,13 @@ fn main() {
 fn hello(_: PotatoRequest) -> PotatoResponse {
     let res = PotatoResponse::new();
     let body = "Hello World!".as_bytes();
-    res.set_status("200 OK")
-        .add_body(body.to_owned())
-        .add_header("Content-Length", &body.len().to_string())
+    res.set_status(StatusCode::OK).add_body(body.to_owned())
 }
 
 fn hi(_: PotatoRequest) -> PotatoResponse {
     let res = PotatoResponse::new();
     let body = "Hi World".as_bytes();
-    res.set_status("200 OK")
-        .add_body(body.to_owned())
-        .add_header("Content-Length", &body.len().to_string())
+    res.set_status(StatusCode::OK).add_body(body.to_owned())
 }
 
 fn simple_add(req: PotatoRequest) -> PotatoResponse {
@@ -63,14 +115,13 @@ fn simple_add(req: PotatoRequest) -> PotatoResponse {
             }
 
             let body = result.to_string().as_bytes().to_owned();
-            res.set_status("200 OK")
-                .add_header("Content-Length", &body.len().to_string())
-                .add_body(body)
+            res.set_status(StatusCode::OK).add_body(body)
         }
-        None => res.set_status("400 Bad Request"),
+        None => res.set_status(StatusCode::BAD_REQUEST),
     }
 }
 
+#[allow(clippy::only_used_in_recursion)]
 fn compute_hanoi(num: i32, from: i32, to: i32, via: i32) {
     if num > 0 {
         compute_hanoi(num - 1, from, via, to);
@@ -89,11 +140,9 @@ fn hanoi(req: PotatoRequest) -> PotatoResponse {
 
             let result = "Success";
             let body = result.to_string().as_bytes().to_owned();
-            res.set_status("200 OK")
-                .add_header("Content-Length", &body.len().to_string())
-                .add_body(body)
+            res.set_status(StatusCode::OK).add_body(body)
         }
-        None => res.set_status("400 Bad Request"),
+        None => res.set_status(StatusCode::BAD_REQUEST),
     }
 }
 
@@ -122,20 +171,18 @@ fn bubble_sort(req: PotatoRequest) -> PotatoResponse {
             let mut result = String::new();
             for k in vec {
                 result.push_str(&k.to_string());
-                result = result + " ";
+                result += " ";
             }
 
             let body = result.to_string().as_bytes().to_owned();
-            res.set_status("200 OK")
-                .add_header("Content-Length", &body.len().to_string())
-                .add_body(body)
+            res.set_status(StatusCode::OK).add_body(body)
         }
-        None => res.set_status("400 Bad Request"),
+        None => res.set_status(StatusCode::BAD_REQUEST),
     }
 }
 
-fn check_file(file: String) -> Result<(), Box<std::error::Error>> {
-    let suspend_file_name = format!("{}{}", STATIC_DIR.to_string(), file);
+fn check_file(file: String) -> Result<(), Box<dyn std::error::Error>> {
+    let suspend_file_name = format!("{}{}", *STATIC_DIR, file);
     let _suspend_file = File::open(suspend_file_name)?;
 
     Ok(())
@@ -143,7 +190,7 @@ fn check_file(file: String) -> Result<(), Box<std::error::Error>> {
 
 fn serve_file(req: PotatoRequest) -> PotatoResponse {
     let res = PotatoResponse::new();
-    let filename = format!("{}{}", STATIC_DIR.to_string(), req.path);
+    let filename = format!("{}{}", *STATIC_DIR, req.path);
 
     let checker = check_file(req.path);
     let mut file: File;
@@ -151,11 +198,14 @@ fn serve_file(req: PotatoRequest) -> PotatoResponse {
         file = File::open(filename).unwrap();
         let mut contents = String::new();
         // read the whole file
-        file.read_to_string(&mut contents);
-        res.set_status("200")
-            .add_body(contents.as_bytes().to_owned())
+        match file.read_to_string(&mut contents) {
+            Ok(_) => res
+                .set_status(StatusCode::OK)
+                .add_body(contents.as_bytes().to_owned()),
+            Err(_) => res.set_status(StatusCode::INTERNAL_SERVER_ERROR),
+        }
     } else {
         eprintln!("{} not found", filename);
-        res.set_status("500")
+        res.set_status(StatusCode::INTERNAL_SERVER_ERROR)
     }
 }
@@ -0,0 +1,819 @@
+use crate::access_log::LogFormat;
+use crate::isolation::{self, seccomp, Capability, IsolationSetting, Namespace};
+use crate::libc;
+use crate::listener;
+use crate::nix::unistd;
+use crate::prep;
+use crate::request::HttpRequestMethod;
+use crate::router::{PotatoRoute, Router};
+use crate::server::{PotatoRequestHandler, PotatoServer};
+use crate::static_files::StaticFiles;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Server configuration read from a TOML file.
+///
+/// ```toml
+/// runtime_dir = "/var/run/user/1000/potato"
+/// isolation = true
+/// bridge_subnet = "10.0.0.0/24"
+/// cgroup_root = "/sys/fs/cgroup/system.slice/potato.service/sandboxes"
+///
+/// [[listener]]
+/// address = "0.0.0.0:8000"
+///
+/// [[listener]]
+/// unix = "/run/potato/potato.sock"
+/// mode = 0o660
+///
+/// [access_log]
+/// path = "/var/log/potato/access.log"
+/// format = "json"
+///
+/// [metrics]
+/// path = "/metrics"
+///
+/// [accounting]
+/// server_timing = true
+///
+/// [timeouts]
+/// header_read = 10
+/// handler = 2.5
+///
+/// [[route]]
+/// method = "POST"
+/// path = "/sort"
+/// handler = "sort"
+/// isolation = { bind_mounts = { "/srv/data" = "data" } }
+///
+/// [[route]]
+/// method = "GET"
+/// path = "/fetch"
+/// handler = "fetch"
+/// isolation = { namespaces = ["user", "pid", "mnt", "ipc", "uts"] }
+///
+/// [[route]]
+/// method = "POST"
+/// path = "/hanoi"
+/// handler = "hanoi"
+/// isolation = { memory_max = 67108864, cpu_max = 0.5, pids_max = 16 }
+///
+/// [[route]]
+/// method = "POST"
+/// path = "/add"
+/// handler = "add"
+/// isolation = { seccomp = { allow = ["read"], action = "kill" } }
+///
+/// [[static]]
+/// path = "/assets"
+/// dir = "/srv/assets"
+/// ```
+///
+/// Handlers are code, routes refer to them by the name they are
+/// registered under in the map given to `into_server`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// defaults to `/var/run/user/{uid}/potato`
+    pub runtime_dir: Option<String>,
+    #[serde(default)]
+    pub isolation: bool,
+    pub bridge_subnet: Option<String>,
+    /// delegated cgroup v2 directory, required by resource limits
+    /// and accounting
+    pub cgroup_root: Option<String>,
+    #[serde(default, rename = "listener")]
+    pub listeners: Vec<ListenerConfig>,
+    #[serde(default)]
+    pub timeouts: TimeoutsConfig,
+    pub access_log: Option<AccessLogConfig>,
+    pub metrics: Option<MetricsConfig>,
+    /// resources used by sandboxes, accounted when the section is there
+    pub accounting: Option<AccountingConfig>,
+    #[serde(default, rename = "route")]
+    pub routes: Vec<RouteConfig>,
+    #[serde(default, rename = "static")]
+    pub static_dirs: Vec<StaticDirConfig>,
+    /// handler for requests no route matches
+    pub default: Option<DefaultConfig>,
+}
+
+/// One of `address`, `unix` or `systemd` must be set
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// IPv4 or IPv6 socket address
+    pub address: Option<String>,
+    /// path of a Unix domain socket
+    pub unix: Option<String>,
+    /// permissions of the Unix domain socket
+    #[serde(default = "default_socket_mode")]
+    pub mode: u32,
+    /// sockets passed by systemd socket activation
+    #[serde(default)]
+    pub systemd: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    pub path: String,
+    /// `common`, `combined` or `json`, `combined` by default
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+/// Prometheus metrics endpoint
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// `/metrics` by default
+    pub path: Option<String>,
+}
+
+impl MetricsConfig {
+    fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or("/metrics")
+    }
+}
+
+/// Accounting of the resources used by sandboxes
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountingConfig {
+    /// also send them as a `Server-Timing` trailer
+    #[serde(default)]
+    pub server_timing: bool,
+}
+
+/// Timeouts in seconds, unset ones keep the server defaults
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub keep_alive: Option<f64>,
+    pub header_read: Option<f64>,
+    pub body_read: Option<f64>,
+    pub write: Option<f64>,
+    pub handler: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub method: String,
+    pub path: String,
+    pub handler: String,
+    pub isolation: Option<IsolationConfig>,
+}
+
+/// Directory served under the `path` prefix
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticDirConfig {
+    pub path: String,
+    pub dir: String,
+    pub isolation: Option<IsolationConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DefaultConfig {
+    pub handler: String,
+    pub isolation: Option<IsolationConfig>,
+}
+
+/// Settings of the sandbox a route runs in, only meaningful with
+/// `isolation = true`. Routes without it then get the default settings.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IsolationConfig {
+    /// host directory -> path in the sandbox rootfs
+    #[serde(default)]
+    pub bind_mounts: HashMap<String, String>,
+    /// handler timeout of the route in seconds
+    pub timeout: Option<f64>,
+    /// namespaces the sandbox gets of its own by their name in
+    /// `/proc/self/ns`, e.g. `["user", "pid", "mnt"]`, all of them by default
+    pub namespaces: Option<Vec<String>>,
+    /// memory limit in bytes
+    pub memory_max: Option<u64>,
+    /// CPU limit in CPUs, e.g. 0.5 for half a CPU
+    pub cpu_max: Option<f64>,
+    /// limit on the number of processes and threads
+    pub pids_max: Option<u64>,
+    /// `MAJ:MIN` of a device -> its limits in the format of `io.max`,
+    /// e.g. `{ "8:0" = "rbps=1048576 wiops=100" }`
+    #[serde(default)]
+    pub io_max: HashMap<String, String>,
+    /// syscall filter, the default deny list when unset
+    pub seccomp: Option<SeccompConfig>,
+    /// capabilities the handler keeps by their name without `CAP_`,
+    /// e.g. `["net_bind_service"]`, none by default
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+/// Syscall filter of a route
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SeccompConfig {
+    /// only let through these syscalls and what answering takes,
+    /// instead of everything but the default deny list
+    pub allow: Option<Vec<String>>,
+    /// `errno` to fail other syscalls with `EPERM`, the default,
+    /// or `kill` to kill the handler
+    pub action: Option<String>,
+    /// let through every syscall
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl SeccompConfig {
+    /// Profile of the filter, `Err` with the first unknown syscall or action
+    fn profile(&self) -> Result<Option<seccomp::Profile>, String> {
+        if self.disabled {
+            return Ok(None);
+        }
+        let profile = match &self.allow {
+            Some(names) => {
+                let names: Vec<&str> = names.iter().map(String::as_str).collect();
+                seccomp::Profile::allow_list(&names)
+                    .map_err(|name| format!("has an unknown syscall {}", name))?
+            }
+            None => seccomp::Profile::deny_list(),
+        };
+        let action = match self.action.as_deref() {
+            None | Some("errno") => seccomp::Action::Errno(libc::EPERM),
+            Some("kill") => seccomp::Action::Kill,
+            Some(action) => return Err(format!("has an unknown seccomp action {}", action)),
+        };
+        Ok(Some(profile.with_action(action)))
+    }
+}
+
+/// Period `cpu_max` is enforced over
+const CPU_MAX_PERIOD: Duration = Duration::from_millis(100);
+
+impl IsolationConfig {
+    fn has_limits(&self) -> bool {
+        self.memory_max.is_some()
+            || self.cpu_max.is_some()
+            || self.pids_max.is_some()
+            || !self.io_max.is_empty()
+    }
+
+    /// Parsed `capabilities`, `Err` with the first unknown name
+    fn capabilities(&self) -> Result<Vec<Capability>, &str> {
+        self.capabilities
+            .iter()
+            .map(|name| Capability::from_name(name).ok_or(name.as_str()))
+            .collect()
+    }
+
+    /// `cpu_max` as a quota of CPU time per period
+    fn cpu_quota(&self) -> Option<Duration> {
+        let cpus = self.cpu_max?;
+        Duration::try_from_secs_f64(CPU_MAX_PERIOD.as_secs_f64() * cpus).ok()
+    }
+
+    /// Parsed `namespaces`, `Err` with the first unknown name
+    fn namespaces(&self) -> Option<Result<Vec<Namespace>, &str>> {
+        let names = self.namespaces.as_ref()?;
+        Some(
+            names
+                .iter()
+                .map(|name| Namespace::from_symlink_name(name).ok_or(name.as_str()))
+                .collect(),
+        )
+    }
+}
+
+fn default_socket_mode() -> u32 {
+    0o660
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, io::Error),
+    Parse(toml::de::Error),
+    /// every problem found by `Config::validate`
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "[{}] {}", path, e),
+            ConfigError::Parse(e) => write!(f, "{}", e),
+            ConfigError::Invalid(problems) => write!(f, "{}", problems.join("\n")),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Config, ConfigError> {
+        toml::from_str(s).map_err(ConfigError::Parse)
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &str) -> Result<Config, ConfigError> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_string(), e))?;
+        contents.parse()
+    }
+
+    /// Check everything `into_server` would otherwise panic on, routes may
+    /// only refer to the handlers named in `handlers`
+    pub fn validate(
+        &self,
+        handlers: &HashMap<&str, PotatoRequestHandler>,
+    ) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if let Some(dir) = &self.runtime_dir {
+            if !Path::new(dir).is_absolute() {
+                problems.push(format!("runtime_dir [{}] must be an absolute path", dir));
+            }
+        }
+        if let Some(root) = &self.cgroup_root {
+            if !Path::new(root).join("cgroup.procs").is_file() {
+                problems.push(format!(
+                    "cgroup_root [{}] is not a cgroup v2 directory",
+                    root
+                ));
+            }
+        }
+        if let Some(subnet) = &self.bridge_subnet {
+            if !prep::is_ipv4_cidr(subnet) {
+                problems.push(format!("bridge_subnet [{}] is not an IPv4 subnet", subnet));
+            }
+        }
+
+        let timeouts = [
+            ("keep_alive", self.timeouts.keep_alive),
+            ("header_read", self.timeouts.header_read),
+            ("body_read", self.timeouts.body_read),
+            ("write", self.timeouts.write),
+            ("handler", self.timeouts.handler),
+        ];
+        for (name, timeout) in timeouts {
+            if timeout.is_some() && timeout.and_then(to_duration).is_none() {
+                problems.push(format!(
+                    "timeouts.{} must be a positive number of seconds",
+                    name
+                ));
+            }
+        }
+
+        if let Some(access_log) = &self.access_log {
+            let path = Path::new(&access_log.path);
+            let dir = match path.parent() {
+                Some(dir) if dir.as_os_str().is_empty() => Some(Path::new(".")),
+                dir => dir.filter(|dir| dir.is_dir()),
+            };
+            // opened for appending, created when missing
+            let writable = |path: &Path| unistd::access(path, unistd::AccessFlags::W_OK).is_ok();
+            match dir {
+                None => problems.push(format!(
+                    "access_log [{}] is not in a directory",
+                    access_log.path
+                )),
+                Some(_) if path.exists() && !writable(path) => {
+                    problems.push(format!("access_log [{}] is not writable", access_log.path))
+                }
+                Some(dir) if !path.exists() && !writable(dir) => problems.push(format!(
+                    "access_log [{}] can't be created in {}",
+                    access_log.path,
+                    dir.display()
+                )),
+                Some(_) => {}
+            }
+        }
+
+        if let Some(metrics) = &self.metrics {
+            let path = metrics.path();
+            problems.extend(validate_path(path).map(|e| format!("metrics [{}] {}", path, e)));
+            if path.contains([':', '*']) {
+                problems.push(format!("metrics [{}] path can't have parameters", path));
+            }
+        }
+
+        if self.accounting.is_some() && self.cgroup_root.is_none() {
+            problems.push("accounting needs a cgroup_root".to_string());
+        }
+
+        if self.listeners.is_empty() {
+            problems.push("no listener configured".to_string());
+        }
+        for (i, listener) in self.listeners.iter().enumerate() {
+            let kinds = [
+                listener.address.is_some(),
+                listener.unix.is_some(),
+                listener.systemd,
+            ];
+            if kinds.iter().filter(|set| **set).count() != 1 {
+                problems.push(format!(
+                    "listener #{} needs exactly one of address, unix or systemd",
+                    i + 1
+                ));
+            }
+            if let Some(address) = &listener.address {
+                if address.parse::<SocketAddr>().is_err() {
+                    problems.push(format!("listener [{}] is not a socket address", address));
+                }
+            }
+            if listener.mode > 0o777 {
+                problems.push(format!(
+                    "listener #{} mode {:o} is not a permission",
+                    i + 1,
+                    listener.mode
+                ));
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for route in &self.routes {
+            let name = format!("route {} {}", route.method, route.path);
+            match HttpRequestMethod::from_str(&route.method) {
+                Some(method) => {
+                    if !seen.insert((method, route.path.as_str())) {
+                        problems.push(format!("[{}] is declared twice", name));
+                    }
+                }
+                None => problems.push(format!("[{}] has an unknown method", name)),
+            }
+            problems.extend(validate_path(&route.path).map(|e| format!("[{}] {}", name, e)));
+            if !handlers.contains_key(route.handler.as_str()) {
+                problems.push(format!(
+                    "[{}] has an unknown handler {}",
+                    name, route.handler
+                ));
+            }
+            problems.extend(self.validate_isolation(&name, route.isolation.as_ref(), false));
+        }
+
+        for static_dir in &self.static_dirs {
+            let name = format!("static {}", static_dir.path);
+            problems.extend(validate_path(&static_dir.path).map(|e| format!("[{}] {}", name, e)));
+            if static_dir.path.contains([':', '*']) {
+                problems.push(format!("[{}] path can't have parameters", name));
+            }
+            if !Path::new(&static_dir.dir).is_dir() {
+                problems.push(format!(
+                    "[{}] dir {} is not a directory",
+                    name, static_dir.dir
+                ));
+            }
+            problems.extend(self.validate_isolation(&name, static_dir.isolation.as_ref(), true));
+        }
+
+        if let Some(default) = &self.default {
+            if !handlers.contains_key(default.handler.as_str()) {
+                problems.push(format!(
+                    "default route has an unknown handler {}",
+                    default.handler
+                ));
+            }
+            problems.extend(self.validate_isolation("default", default.isolation.as_ref(), false));
+        }
+
+        problems.extend(self.route_conflicts());
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigError::Invalid(problems)),
+        }
+    }
+
+    /// Insert the routes `into_server` would in a router of their own, to
+    /// find those clashing with another one or served by two of them, e.g.
+    /// a route under a static directory. Problems of a single route are
+    /// left to `validate`.
+    fn route_conflicts(&self) -> Vec<String> {
+        let mut routes = Vec::new();
+        if let Some(metrics) = &self.metrics {
+            let path = metrics.path();
+            if validate_path(path).is_none() && !path.contains([':', '*']) {
+                routes.push((
+                    HttpRequestMethod::GET,
+                    path.to_string(),
+                    format!("metrics {}", path),
+                ));
+            }
+        }
+        for route in &self.routes {
+            if let (Some(method), None) = (
+                HttpRequestMethod::from_str(&route.method),
+                validate_path(&route.path),
+            ) {
+                let name = format!("route {} {}", route.method, route.path);
+                routes.push((method, route.path.clone(), name));
+            }
+        }
+        for static_dir in &self.static_dirs {
+            if validate_path(&static_dir.path).is_some() || static_dir.path.contains([':', '*']) {
+                continue;
+            }
+            let path = format!("{}/*path", static_dir.path.trim_end_matches('/'));
+            for method in [HttpRequestMethod::GET, HttpRequestMethod::HEAD] {
+                routes.push((method, path.clone(), format!("static {}", static_dir.path)));
+            }
+        }
+
+        let mut problems = Vec::new();
+        let mut router = Router::new();
+        let mut names = HashMap::new();
+        for (method, path, name) in routes {
+            let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+            let pattern = format!("/{}", segments.join("/"));
+            // identical routes replace each other, a route declared twice
+            // is already reported
+            match names.get(&(method, pattern.clone())) {
+                Some(other) if *other == name => continue,
+                Some(other) => {
+                    problems.push(format!("[{}] is also served by [{}]", name, other));
+                    continue;
+                }
+                None => {}
+            }
+            match router.insert(&PotatoRoute::new(method, &pattern), ()) {
+                Ok(()) => {
+                    names.insert((method, pattern), name);
+                }
+                Err(e) => problems.push(format!("[{}] {}", name, e)),
+            }
+        }
+        // a static directory is served by two routes
+        problems.dedup();
+        problems
+    }
+
+    /// `bind_mounted` tells whether the route bind mounts a directory
+    /// of its own, like static directories do
+    fn validate_isolation(
+        &self,
+        name: &str,
+        isolation: Option<&IsolationConfig>,
+        bind_mounted: bool,
+    ) -> Vec<String> {
+        let isolation = match isolation {
+            Some(isolation) => isolation,
+            None => return Vec::new(),
+        };
+        let mut problems = Vec::new();
+        if isolation.timeout.is_some() && isolation.timeout.and_then(to_duration).is_none() {
+            problems.push(format!(
+                "[{}] timeout must be a positive number of seconds",
+                name
+            ));
+        }
+        if !self.isolation {
+            problems.push(format!(
+                "[{}] has isolation settings but isolation is disabled",
+                name
+            ));
+        }
+        for (src, target) in &isolation.bind_mounts {
+            if !Path::new(src).is_dir() {
+                problems.push(format!(
+                    "[{}] bind mount source {} is not a directory",
+                    name, src
+                ));
+            }
+            let target = Path::new(target);
+            if target.is_absolute() || target.components().any(|c| c.as_os_str() == "..") {
+                problems.push(format!(
+                    "[{}] bind mount target {} must be relative to the rootfs",
+                    name,
+                    target.display()
+                ));
+            }
+        }
+        match isolation.namespaces() {
+            Some(Ok(namespaces)) => {
+                let namespaces = namespaces.into_iter().collect();
+                let has_bind_mounts = bind_mounted || !isolation.bind_mounts.is_empty();
+                if let Some(problem) = isolation::namespace_problem(&namespaces, has_bind_mounts) {
+                    problems.push(format!("[{}] {}", name, problem));
+                }
+            }
+            Some(Err(unknown)) => {
+                problems.push(format!("[{}] has an unknown namespace {}", name, unknown));
+            }
+            None => {}
+        }
+
+        if let Some(Err(problem)) = isolation.seccomp.as_ref().map(SeccompConfig::profile) {
+            problems.push(format!("[{}] {}", name, problem));
+        }
+        if let Err(unknown) = isolation.capabilities() {
+            problems.push(format!("[{}] has an unknown capability {}", name, unknown));
+        }
+
+        if isolation.has_limits() && self.cgroup_root.is_none() {
+            problems.push(format!("[{}] resource limits need a cgroup_root", name));
+        }
+        if isolation.memory_max == Some(0) {
+            problems.push(format!("[{}] memory_max must not be zero", name));
+        }
+        if isolation.pids_max == Some(0) {
+            problems.push(format!("[{}] pids_max must not be zero", name));
+        }
+        if isolation.cpu_max.is_some() {
+            let problem = match isolation.cpu_quota() {
+                Some(quota) => isolation::cpu_max_problem(quota, CPU_MAX_PERIOD),
+                None => Some("cpu max must be a positive number of CPUs"),
+            };
+            problems.extend(problem.map(|e| format!("[{}] {}", name, e)));
+        }
+        for (device, limits) in &isolation.io_max {
+            let problem = isolation::io_max_problem(device, limits);
+            problems.extend(problem.map(|e| format!("[{}] {}", name, e)));
+        }
+        problems
+    }
+
+    /// Validate the configuration and build the server it describes
+    pub fn into_server(
+        self,
+        handlers: &HashMap<&str, PotatoRequestHandler>,
+    ) -> Result<PotatoServer, ConfigError> {
+        self.validate(handlers)?;
+
+        let runtime_dir = match &self.runtime_dir {
+            Some(dir) => dir.clone(),
+            None => format!("/var/run/user/{}/potato", unistd::getuid()),
+        };
+        // the configured listeners always replace the one on this port
+        let mut server = PotatoServer::new("0", &runtime_dir, self.isolation);
+        if let Some(subnet) = &self.bridge_subnet {
+            server = server.set_bridge_subnet(subnet);
+        }
+        if let Some(root) = &self.cgroup_root {
+            server = server.set_cgroup_root(root);
+        }
+
+        if let Some(access_log) = &self.access_log {
+            server = server.set_access_log(&access_log.path, access_log.format);
+        }
+        if let Some(metrics) = &self.metrics {
+            server = server.enable_metrics(metrics.path());
+        }
+        match &self.accounting {
+            Some(accounting) if accounting.server_timing => {
+                server = server.enable_server_timing();
+            }
+            Some(_) => server = server.enable_accounting(),
+            None => {}
+        }
+
+        let timeouts = &self.timeouts;
+        if let Some(timeout) = timeouts.keep_alive.and_then(to_duration) {
+            server = server.set_keep_alive_timeout(timeout);
+        }
+        if let Some(timeout) = timeouts.header_read.and_then(to_duration) {
+            server = server.set_header_read_timeout(timeout);
+        }
+        if let Some(timeout) = timeouts.body_read.and_then(to_duration) {
+            server = server.set_body_read_timeout(timeout);
+        }
+        if let Some(timeout) = timeouts.write.and_then(to_duration) {
+            server = server.set_write_timeout(timeout);
+        }
+        if let Some(timeout) = timeouts.handler.and_then(to_duration) {
+            server = server.set_handler_timeout(timeout);
+        }
+
+        for listener in &self.listeners {
+            server = match (&listener.address, &listener.unix) {
+                (Some(address), _) => server.bind(address),
+                (_, Some(path)) => server.bind_unix(path, listener.mode),
+                _ => {
+                    let fds = listener::systemd_listen_fds();
+                    if fds.is_empty() {
+                        return Err(ConfigError::Invalid(vec![
+                            "systemd listener but no socket was passed, see LISTEN_FDS".to_string(),
+                        ]));
+                    }
+                    fds.into_iter()
+                        .fold(server, |server, fd| server.bind_fd(fd))
+                }
+            };
+        }
+
+        for route in &self.routes {
+            let method = HttpRequestMethod::from_str(&route.method).unwrap(); // safe unwrap
+            let handler = Arc::clone(&handlers[route.handler.as_str()]);
+            let isolation = self.isolation_setting(route.isolation.as_ref());
+            server = server.add_handler_with_isolation(
+                method,
+                &route.path,
+                move |req| handler.handle(req),
+                isolation,
+            );
+        }
+
+        for (i, static_dir) in self.static_dirs.iter().enumerate() {
+            let path = format!("{}/*path", static_dir.path.trim_end_matches('/'));
+            // in a sandbox the directory is bind mounted in the rootfs
+            let (files, isolation) = match self.isolation_setting(static_dir.isolation.as_ref()) {
+                Some(isolation) => {
+                    let target = format!("static/{}", i);
+                    let files = StaticFiles::new(&format!("/{}", target));
+                    (
+                        files,
+                        Some(isolation.add_bind_mount_point(&static_dir.dir, &target)),
+                    )
+                }
+                None => (StaticFiles::new(&static_dir.dir), None),
+            };
+            for method in [HttpRequestMethod::GET, HttpRequestMethod::HEAD] {
+                server = server.add_handler_with_isolation(
+                    method,
+                    &path,
+                    files.clone(),
+                    isolation.clone(),
+                );
+            }
+        }
+
+        if let Some(default) = &self.default {
+            let handler = Arc::clone(&handlers[default.handler.as_str()]);
+            let isolation = self.isolation_setting(default.isolation.as_ref());
+            server = server
+                .add_default_handler_with_isolation(move |req| handler.handle(req), isolation);
+        }
+
+        Ok(server)
+    }
+
+    /// Setting for a route, every route is isolated when isolation is enabled
+    fn isolation_setting(&self, isolation: Option<&IsolationConfig>) -> Option<IsolationSetting> {
+        if !self.isolation {
+            return None;
+        }
+        let mut setting = IsolationSetting::new();
+        if let Some(isolation) = isolation {
+            for (src, target) in &isolation.bind_mounts {
+                setting = setting.add_bind_mount_point(src, target);
+            }
+            if let Some(timeout) = isolation.timeout.and_then(to_duration) {
+                setting = setting.set_timeout(timeout);
+            }
+            if let Some(Ok(namespaces)) = isolation.namespaces() {
+                setting = setting.set_namespaces(&namespaces);
+            }
+            if let Some(bytes) = isolation.memory_max {
+                setting = setting.set_memory_max(bytes);
+            }
+            if let Some(quota) = isolation.cpu_quota() {
+                setting = setting.set_cpu_max(quota, CPU_MAX_PERIOD);
+            }
+            if let Some(max) = isolation.pids_max {
+                setting = setting.set_pids_max(max);
+            }
+            for (device, limits) in &isolation.io_max {
+                setting = setting.add_io_max(device, limits);
+            }
+            match isolation.seccomp.as_ref().map(SeccompConfig::profile) {
+                Some(Ok(Some(profile))) => setting = setting.set_seccomp(profile),
+                Some(Ok(None)) => setting = setting.disable_seccomp(),
+                _ => {}
+            }
+            if let Ok(capabilities) = isolation.capabilities() {
+                setting = setting.set_capabilities(&capabilities);
+            }
+        }
+        Some(setting)
+    }
+}
+
+fn to_duration(secs: f64) -> Option<Duration> {
+    Duration::try_from_secs_f64(secs)
+        .ok()
+        .filter(|duration| !duration.is_zero())
+}
+
+fn validate_path(path: &str) -> Option<&'static str> {
+    if !path.starts_with('/') {
+        return Some("path must start with /");
+    }
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match segments.iter().position(|s| s.starts_with('*')) {
+        Some(i) if i != segments.len() - 1 => Some("wildcard must be the last segment"),
+        _ => None,
+    }
+}
@@ -0,0 +1,44 @@
+use crate::request::PotatoRequest;
+use crate::response::PotatoResponse;
+use std::future::Future;
+use std::pin::Pin;
+
+/// Something that turns a request into a response.
+///
+/// Implemented for every `Fn(PotatoRequest) -> PotatoResponse`, so plain
+/// functions as well as closures capturing configuration can be registered.
+/// Handlers are shared between connection threads, hence `Send + Sync`.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, req: PotatoRequest) -> PotatoResponse;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(PotatoRequest) -> PotatoResponse + Send + Sync + 'static,
+{
+    fn handle(&self, req: PotatoRequest) -> PotatoResponse {
+        self(req)
+    }
+}
+
+/// Response being computed by an `AsyncHandler`
+pub type HandlerFuture = Pin<Box<dyn Future<Output = PotatoResponse> + Send + 'static>>;
+
+/// Handler for the async server, see `PotatoServer::start_async`.
+///
+/// Implemented for every `Fn(PotatoRequest) -> impl Future<Output = PotatoResponse>`,
+/// e.g. `|req| async move { ... }`. Async handlers run on the tokio runtime of
+/// the server process, they can neither be isolated nor wrapped in middlewares.
+pub trait AsyncHandler: Send + Sync + 'static {
+    fn handle(&self, req: PotatoRequest) -> HandlerFuture;
+}
+
+impl<F, Fut> AsyncHandler for F
+where
+    F: Fn(PotatoRequest) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = PotatoResponse> + Send + 'static,
+{
+    fn handle(&self, req: PotatoRequest) -> HandlerFuture {
+        Box::pin(self(req))
+    }
+}
@@ -0,0 +1,136 @@
+use std::fmt;
+
+/// Ordered collection of header fields.
+///
+/// Names are compared case-insensitively but kept as given, and a name can
+/// appear several times, as with `Cookie` on requests or `Set-Cookie` on
+/// responses.
+#[derive(Clone, Default)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> HeaderMap {
+        HeaderMap {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Add a value for `name`, keeping any value already present
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    /// Set `name` to a single value, replacing every value already present
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    /// Remove every value of `name`
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    /// First value of `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All values of `name` in the order they were added
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Iterate over every `(name, value)` pair in order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl fmt::Debug for HeaderMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// Whether `name` is a valid field name, a `token` as of RFC 7230 section 3.2.6
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(|b| {
+            b.is_ascii_alphanumeric()
+                || matches!(
+                    b,
+                    b'!' | b'#'
+                        | b'$'
+                        | b'%'
+                        | b'&'
+                        | b'\''
+                        | b'*'
+                        | b'+'
+                        | b'-'
+                        | b'.'
+                        | b'^'
+                        | b'_'
+                        | b'`'
+                        | b'|'
+                        | b'~'
+                )
+        })
+}
+
+/// Whether `value` is a valid field value: no control characters
+/// other than horizontal tab, in particular no CR or LF
+pub fn is_valid_value(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|b| b == b'\t' || (b >= 0x20 && b != 0x7f))
+}
+
+/// Header rejected because it could corrupt the framing of a message
+#[derive(Debug)]
+pub enum InvalidHeader {
+    Name(String),
+    Value(String),
+}
+
+impl fmt::Display for InvalidHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidHeader::Name(name) => write!(f, "[{:?}] invalid header name", name),
+            InvalidHeader::Value(name) => write!(f, "[{}] invalid header value", name),
+        }
+    }
+}
+
+impl std::error::Error for InvalidHeader {}
+
+/// Check a header before it goes on the wire
+pub fn validate(name: &str, value: &str) -> Result<(), InvalidHeader> {
+    if !is_valid_name(name) {
+        return Err(InvalidHeader::Name(name.to_string()));
+    }
+    if !is_valid_value(value) {
+        return Err(InvalidHeader::Value(name.to_string()));
+    }
+    Ok(())
+}
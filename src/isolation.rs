@@ -1,18 +1,82 @@
-use crate::{request::PotatoRequest, server::PotatoRequestHandler};
-use libpotato::{clone, libc, nix, signal, signal_hook as sighook};
-use nix::mount::{mount, umount, MsFlags};
-use nix::unistd;
-use std::collections::HashMap;
+use crate::request::{HttpRequestMethod, PotatoRequest};
+use crate::response::last_chunk;
+use crate::server::{error_response, PotatoRequestHandler};
+use crate::status::StatusCode;
+use libpotato::cgroup::{Cgroup, Usage};
+use libpotato::{capability, clone, libc, nix, signal, signal_hook as sighook};
+use nix::fcntl::OFlag;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sched::CloneFlags;
+use nix::sys::mman::{mmap, MapFlags, ProtFlags};
+use nix::sys::signal::Signal;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{self, Pid};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::prelude::*;
-use std::net::TcpStream;
-use std::os::unix::io::AsRawFd;
-use std::path::Path;
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub use libpotato::capability::Capability;
+pub use libpotato::namespace::Namespace;
+pub use libpotato::seccomp;
+
+/// Number of sandbox cgroups created so far, used to name them
+static CGROUPS_CREATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Signals the server process handles itself, a sandbox must not
+/// inherit their handlers or it could drive the server by raising them
+pub(crate) const HOST_SIGNALS: [Signal; 4] = [
+    Signal::SIGTERM,
+    Signal::SIGINT,
+    Signal::SIGHUP,
+    Signal::SIGUSR2,
+];
+
+/// Exit code of a worker that failed writing its response
+const WRITE_FAILED: isize = 2;
 
 #[derive(Clone)]
 pub struct IsolationSetting {
     pub rootfs_path: String,
     pub mount_points: HashMap<String, String>,
+    /// the handler is killed and `504` sent once it has run that long,
+    /// the server fills in its handler timeout when unset
+    pub timeout: Option<Duration>,
+    /// namespaces the sandbox gets of its own, all of them by default
+    pub namespaces: HashSet<Namespace>,
+    /// `memory.max` of the sandbox cgroup in bytes
+    pub memory_max: Option<u64>,
+    /// `cpu.max` of the sandbox cgroup, CPU time allowed in each period
+    pub cpu_max: Option<(Duration, Duration)>,
+    /// `pids.max` of the sandbox cgroup
+    pub pids_max: Option<u64>,
+    /// `io.max` of the sandbox cgroup, `MAJ:MIN` of a device to its limits
+    pub io_max: HashMap<String, String>,
+    /// delegated cgroup the sandbox cgroups are created under,
+    /// the server fills in its cgroup root when unset
+    pub cgroup_root: Option<String>,
+    /// read the resources the sandbox used from its cgroup once it exited
+    pub accounting: bool,
+    /// send the resources used as a `Server-Timing` trailer, implies `accounting`
+    pub server_timing: bool,
+    /// syscall filter the handler runs under, `seccomp::Profile::deny_list`
+    /// by default
+    pub seccomp: Option<seccomp::Profile>,
+    /// capabilities the handler keeps in the namespaces of the sandbox,
+    /// none by default
+    pub capabilities: HashSet<Capability>,
+}
+
+impl Default for IsolationSetting {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl IsolationSetting {
@@ -20,19 +84,203 @@ impl IsolationSetting {
         IsolationSetting {
             rootfs_path: "".to_string(),
             mount_points: HashMap::new(),
+            timeout: None,
+            namespaces: Namespace::ALL.iter().copied().collect(),
+            memory_max: None,
+            cpu_max: None,
+            pids_max: None,
+            io_max: HashMap::new(),
+            cgroup_root: None,
+            accounting: false,
+            server_timing: false,
+            seccomp: Some(seccomp::Profile::deny_list()),
+            capabilities: HashSet::new(),
         }
     }
 
     pub fn add_bind_mount_point(mut self, src: &str, target: &str) -> IsolationSetting {
         assert!(
             Path::new(src).exists(),
-            format!("[{}] bind mount source not exist", src)
+            "[{}] bind mount source not exist",
+            src
+        );
+        assert!(
+            self.namespaces.contains(&Namespace::Mount),
+            "[{}] bind mount needs a mount namespace",
+            src
         );
         self.mount_points
             .insert(src.to_string(), target.to_string());
         self
     }
 
+    /// Give the handler at most `timeout` to answer, overriding
+    /// the handler timeout of the server for this route
+    pub fn set_timeout(mut self, timeout: Duration) -> IsolationSetting {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Only give the sandbox its own `namespaces`, sharing the others with
+    /// the server. E.g. a route making outbound connections can leave out
+    /// `Namespace::Network`. Panics on a set `namespace_problem` rejects.
+    ///
+    /// Without `Namespace::Pid`, processes the handler starts are not
+    /// killed along with the sandbox.
+    pub fn set_namespaces(mut self, namespaces: &[Namespace]) -> IsolationSetting {
+        let namespaces = namespaces.iter().copied().collect();
+        if let Some(problem) = namespace_problem(&namespaces, !self.mount_points.is_empty()) {
+            panic!("[{:?}] {}", namespaces, problem);
+        }
+        self.namespaces = namespaces;
+        self
+    }
+
+    /// Limit the memory of the sandbox to `bytes`, past it the kernel
+    /// reclaims memory and kills the handler when it can't
+    pub fn set_memory_max(mut self, bytes: u64) -> IsolationSetting {
+        assert!(bytes > 0, "memory max must not be zero");
+        self.memory_max = Some(bytes);
+        self
+    }
+
+    /// Let the sandbox use at most `quota` of CPU time in each `period`,
+    /// e.g. 50ms every 100ms for half a CPU
+    pub fn set_cpu_max(mut self, quota: Duration, period: Duration) -> IsolationSetting {
+        if let Some(problem) = cpu_max_problem(quota, period) {
+            panic!("[{:?} {:?}] {}", quota, period, problem);
+        }
+        self.cpu_max = Some((quota, period));
+        self
+    }
+
+    /// Limit the number of processes and threads of the sandbox to `max`
+    pub fn set_pids_max(mut self, max: u64) -> IsolationSetting {
+        assert!(max > 0, "pids max must not be zero");
+        self.pids_max = Some(max);
+        self
+    }
+
+    /// Limit the io of the sandbox on `device`, given as `MAJ:MIN`, to
+    /// `limits` in the format of `io.max`, e.g. `rbps=1048576 wiops=100`
+    pub fn add_io_max(mut self, device: &str, limits: &str) -> IsolationSetting {
+        if let Some(problem) = io_max_problem(device, limits) {
+            panic!("[{} {}] {}", device, limits, problem);
+        }
+        self.io_max.insert(device.to_string(), limits.to_string());
+        self
+    }
+
+    /// Run the handler under the syscall filter `profile`, e.g. an allow
+    /// list of the syscalls it needs killing it on any other one:
+    /// `seccomp::Profile::allow_list(&["read"])?.with_action(seccomp::Action::Kill)`.
+    /// The syscalls it fails are reported on stderr once the handler exited,
+    /// the handler must leave the SIGSYS handler of the sandbox alone.
+    pub fn set_seccomp(mut self, profile: seccomp::Profile) -> IsolationSetting {
+        self.seccomp = Some(profile);
+        self
+    }
+
+    /// Let the handler make any syscall, not even filtering the default deny list
+    pub fn disable_seccomp(mut self) -> IsolationSetting {
+        self.seccomp = None;
+        self
+    }
+
+    /// Let the handler keep `capabilities`, e.g. `Capability::NetBindService`
+    /// to listen on a low port in the network namespace of the sandbox.
+    /// Every other one is dropped and none can be gained back.
+    pub fn set_capabilities(mut self, capabilities: &[Capability]) -> IsolationSetting {
+        self.capabilities = capabilities.iter().copied().collect();
+        self
+    }
+
+    /// Account the CPU time, peak memory and io of the sandbox, which
+    /// end up in the access log and the metrics
+    pub fn enable_accounting(mut self) -> IsolationSetting {
+        self.accounting = true;
+        self
+    }
+
+    /// Account the sandbox and send what it used as a `Server-Timing`
+    /// trailer, for HTTP/1.1 responses with a body. The response is then
+    /// sent chunked, ended by the init process once the worker exited.
+    pub fn enable_server_timing(mut self) -> IsolationSetting {
+        self.accounting = true;
+        self.server_timing = true;
+        self
+    }
+
+    /// Whether the sandbox has any cgroup limit
+    pub fn has_limits(&self) -> bool {
+        self.memory_max.is_some()
+            || self.cpu_max.is_some()
+            || self.pids_max.is_some()
+            || !self.io_max.is_empty()
+    }
+
+    /// Controllers the limits need enabled in the cgroup root
+    pub fn controllers(&self) -> Vec<&'static str> {
+        let mut controllers = Vec::new();
+        if self.memory_max.is_some() {
+            controllers.push("memory");
+        }
+        if self.cpu_max.is_some() {
+            controllers.push("cpu");
+        }
+        if self.pids_max.is_some() {
+            controllers.push("pids");
+        }
+        if !self.io_max.is_empty() {
+            controllers.push("io");
+        }
+        controllers
+    }
+
+    /// Whether the sandbox runs in a cgroup of its own
+    pub fn has_cgroup(&self) -> bool {
+        self.has_limits() || self.accounting
+    }
+
+    /// Create the cgroup of the sandbox named `name` with the limits applied
+    fn create_cgroup(&self, name: &str) -> Result<Cgroup, std::io::Error> {
+        let root = self
+            .cgroup_root
+            .as_ref()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no cgroup root"))?;
+        let cgroup = Cgroup::create(Path::new(root), name)?;
+        let mut limits = Vec::new();
+        if let Some(bytes) = self.memory_max {
+            limits.push(("memory.max", bytes.to_string()));
+        }
+        if let Some((quota, period)) = self.cpu_max {
+            let max = format!("{} {}", quota.as_micros(), period.as_micros());
+            limits.push(("cpu.max", max));
+        }
+        if let Some(max) = self.pids_max {
+            limits.push(("pids.max", max.to_string()));
+        }
+        for (device, max) in &self.io_max {
+            limits.push(("io.max", format!("{} {}", device, max)));
+        }
+        for (file, value) in limits {
+            if let Err(e) = cgroup.write(file, &value) {
+                let _ = cgroup.remove();
+                return Err(e);
+            }
+        }
+        Ok(cgroup)
+    }
+
+    /// Flags to clone the sandbox init process with
+    fn clone_flags(&self) -> libc::c_int {
+        let flags = self
+            .namespaces
+            .iter()
+            .fold(CloneFlags::empty(), |flags, ns| flags | ns.to_clone_flag());
+        flags.bits() | libc::SIGCHLD
+    }
+
     /// Bind mounts all `src` to `target` in `mount_points`.
     /// This will silenly refuse to mount if src is not a path to a directory
     pub fn mount_all(self) -> Vec<String> {
@@ -41,13 +289,15 @@ impl IsolationSetting {
             let mnt_target = format!("{}/{}", self.rootfs_path, target);
             if Path::new(&src).is_dir() {
                 fs::create_dir_all(&mnt_target).unwrap();
-                if let Ok(_) = mount(
+                if mount(
                     Some(src.as_str()),
                     mnt_target.as_str(),
                     None::<&str>,
                     MsFlags::MS_BIND,
                     None::<&str>,
-                ) {
+                )
+                .is_ok()
+                {
                     mounted.push(mnt_target)
                 }
             }
@@ -56,43 +306,334 @@ impl IsolationSetting {
     }
 }
 
+/// Why a sandbox can't run with `namespaces`, `None` when it can
+pub fn namespace_problem(
+    namespaces: &HashSet<Namespace>,
+    has_bind_mounts: bool,
+) -> Option<&'static str> {
+    if has_bind_mounts && !namespaces.contains(&Namespace::Mount) {
+        // the bind mounts would show up on the host
+        return Some("bind mounts need a mount namespace");
+    }
+    if !namespaces.contains(&Namespace::User) && !unistd::geteuid().is_root() {
+        // creating namespaces and chroot take privileges a user namespace would grant
+        return Some("namespaces without a user namespace need the server to run as root");
+    }
+    None
+}
+
+/// Why `cpu.max` can't be `quota` in each `period`, `None` when it can
+pub fn cpu_max_problem(quota: Duration, period: Duration) -> Option<&'static str> {
+    let (quota, period) = (quota.as_micros(), period.as_micros());
+    if !(1000..=1_000_000).contains(&period) {
+        return Some("cpu max period must be between 1ms and 1s");
+    }
+    if quota < 1000 {
+        return Some("cpu max quota must be at least 1ms");
+    }
+    None
+}
+
+/// Why `io.max` of `device` can't be `limits`, `None` when it can
+pub fn io_max_problem(device: &str, limits: &str) -> Option<&'static str> {
+    let numbers = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    match device.split_once(':') {
+        Some((major, minor)) if numbers(major) && numbers(minor) => {}
+        _ => return Some("io max device must be given as MAJ:MIN"),
+    }
+    let mut keys = limits.split_whitespace().peekable();
+    if keys.peek().is_none() {
+        return Some("io max needs at least one limit");
+    }
+    for limit in keys {
+        match limit.split_once('=') {
+            Some(("rbps" | "wbps" | "riops" | "wiops", value))
+                if value == "max" || numbers(value) => {}
+            _ => {
+                return Some("io max limits are rbps, wbps, riops or wiops set to a number or max")
+            }
+        }
+    }
+    None
+}
+
+/// Handle on the init process of a running sandbox.
+///
+/// The init process exits once the handler is done and the sandbox has been
+/// cleaned up. Its pidfd becomes readable at that point, which lets callers
+/// wait for it with `poll`/`epoll` instead of ignoring SIGCHLD. Unless
+/// SIGCHLD is ignored the exited init still has to be reaped with `waitpid`.
+pub struct Sandbox {
+    pid: i32,
+    pidfd: RawFd,
+    rootfs: String,
+    mount_targets: Vec<String>,
+    report: Option<ReportReader>,
+    cgroup: Option<Arc<SandboxCgroup>>,
+}
+
+/// Cgroup of a sandbox, removed once neither the sandbox handle nor
+/// its report reader need it. The init process can't remove the
+/// cgroup it is in.
+struct SandboxCgroup(Cgroup);
+
+impl Drop for SandboxCgroup {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.remove() {
+            eprintln!(
+                "[{}] failed removing cgroup: {}",
+                self.0.path().display(),
+                e
+            );
+        }
+    }
+}
+
+/// What a sandbox reports about the response it sent
+#[derive(Clone, Copy, Debug)]
+pub struct SandboxReport {
+    pub status: u16,
+    /// bytes of the response written, headers included
+    pub bytes: usize,
+    /// time from cloning the sandbox to calling the handler,
+    /// `None` when the sandbox answered in place of the handler
+    pub setup: Option<Duration>,
+    /// when the report arrived, i.e. the response was sent
+    pub answered: Instant,
+    /// time from the report to every process of the sandbox being gone
+    pub teardown: Duration,
+    /// resources the sandbox used, when accounted
+    pub usage: Option<Usage>,
+}
+
+/// Read end of the pipe a sandbox reports on once it answered
+pub struct ReportReader {
+    pipe: fs::File,
+    /// monotonic clock reading taken before cloning the sandbox
+    cloned: Duration,
+    /// cgroup the usage is read from, when accounted
+    cgroup: Option<Arc<SandboxCgroup>>,
+}
+
+impl ReportReader {
+    /// Block until every process of the sandbox has exited, regardless of
+    /// other sandboxes. `None` when the sandbox died before reporting,
+    /// e.g. when killed at shutdown.
+    pub fn wait(mut self) -> Option<SandboxReport> {
+        let mut report = Vec::new();
+        let mut answered = None;
+        let mut buf = [0; 128];
+        loop {
+            match self.pipe.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => report.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => return None,
+            }
+            if answered.is_none() && report.contains(&b'\n') {
+                answered = Some(Instant::now());
+            }
+        }
+        let answered = answered?;
+        let teardown = answered.elapsed();
+        let usage = self
+            .cgroup
+            .as_ref()
+            .and_then(|cgroup| cgroup.0.usage().ok());
+
+        let report = String::from_utf8(report).ok()?;
+        // the init process adds the bytes of the trailers it sent
+        // for the worker as `+bytes` lines
+        let (trailers, reports): (Vec<&str>, Vec<&str>) =
+            report.lines().partition(|line| line.starts_with('+'));
+        let trailers: usize = trailers
+            .iter()
+            .filter_map(|line| line[1..].parse::<usize>().ok())
+            .sum();
+        let mut fields = reports.last()?.split(' ');
+        let status = fields.next()?.parse().ok()?;
+        let bytes = fields.next()?.parse::<usize>().ok()? + trailers;
+        let called: u64 = fields.next()?.parse().ok()?;
+        let setup = match called {
+            0 => None,
+            called => Duration::from_nanos(called).checked_sub(self.cloned),
+        };
+        Some(SandboxReport {
+            status,
+            bytes,
+            setup,
+            answered,
+            teardown,
+            usage,
+        })
+    }
+}
+
+impl Sandbox {
+    /// PID of the init process, as seen from the host
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    pub fn rootfs(&self) -> &str {
+        &self.rootfs
+    }
+
+    /// Number of the sandbox directory in the runtime directory
+    pub fn dir_number(&self) -> Option<usize> {
+        Path::new(&self.rootfs).file_name()?.to_str()?.parse().ok()
+    }
+
+    /// Take the reader of the sandbox report, only the first call gets it
+    pub fn take_report(&mut self) -> Option<ReportReader> {
+        self.report.take()
+    }
+
+    /// Whether the init process has exited, never blocks
+    pub fn has_exited(&self) -> bool {
+        let mut fds = [PollFd::new(self.pidfd, PollFlags::POLLIN)];
+        matches!(poll(&mut fds, 0), Ok(n) if n > 0)
+    }
+
+    /// Kill the whole sandbox, killing its init takes down
+    /// every other process of its pid namespace
+    pub fn kill(&self) -> Result<(), nix::Error> {
+        if let Some(cgroup) = &self.cgroup {
+            // gets processes outside of the pid namespace too
+            let _ = cgroup.0.kill();
+        }
+        signal::pidfd_send_signal(self.pidfd, Signal::SIGKILL)
+    }
+
+    /// Do what the init process does on its way out, for a sandbox that
+    /// was killed before it could: unmount the bind mounts and remove the
+    /// rootfs. Must only be called once the sandbox has exited.
+    pub fn cleanup(&self) {
+        for target in &self.mount_targets {
+            // usually gone along with the mount namespace of the sandbox
+            let _ = umount2(target.as_str(), MntFlags::MNT_DETACH);
+        }
+        let _ = fs::remove_dir_all(&self.rootfs);
+    }
+}
+
+impl AsRawFd for Sandbox {
+    fn as_raw_fd(&self) -> RawFd {
+        self.pidfd
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.pidfd) };
+    }
+}
+
 /// NOTE: becareful of what you handler do.
 /// Your handler will be execute in new process in different namespaces
 /// and might cause unexpected behavior.
 /// A common case is when your handler is dealing with system resources
+/// or with state it captured, the handler only ever sees and changes
+/// a copy of it
 ///
 /// Thread that calls this function will be made to
 /// automatically mask SIGCONT before calling `clone`
 /// so that the new init process will inherit the signal masks
 ///
-/// On failure return the ownership of TcpStream for furthur error handling
-pub fn isolate_req(
-    mut stream: TcpStream,
+/// The response is written to the socket behind `stream`, so it must not
+/// carry any state of its own, e.g. a TLS session has to be terminated
+/// outside the server, as the sandbox starts with a copy of its memory.
+///
+/// On success return a handle on the sandbox init process,
+/// on failure return the ownership of the stream for furthur error handling
+pub fn isolate_req<S: AsRawFd>(
+    stream: S,
     req: PotatoRequest,
     handler: PotatoRequestHandler,
     isolation_setting: IsolationSetting,
-) -> Result<(), TcpStream> {
+) -> Result<Sandbox, S> {
     let chrootfs = isolation_setting.rootfs_path.clone();
     let cleanup_fs = isolation_setting.rootfs_path.clone();
+    let rootfs = isolation_setting.rootfs_path.clone();
+    let mount_targets = isolation_setting
+        .mount_points
+        .values()
+        .map(|target| format!("{}/{}", rootfs, target))
+        .collect();
+    let timeout = isolation_setting.timeout;
+    let timeout_response = error_response(StatusCode::GATEWAY_TIMEOUT, "Handler timed out")
+        .set_header("Connection", "close")
+        .to_http_response(req.method);
+    let failure_response = error_response(StatusCode::INTERNAL_SERVER_ERROR, "Handler failure")
+        .set_header("Connection", "close")
+        .to_http_response(req.method);
     const STACK_SIZE: usize = 1024 * 1024;
+    let cloned = monotonic_now();
 
-    // important: hold a fd value to close dangle connection
+    // never reused, unlike rootfs numbers, so the handle on an exited
+    // sandbox can't remove the cgroup of a newer one
+    let cgroup = match isolation_setting.has_cgroup() {
+        true => {
+            let n = CGROUPS_CREATED.fetch_add(1, Ordering::Relaxed);
+            let name = format!("sandbox-{}-{}", std::process::id(), n);
+            match isolation_setting.create_cgroup(&name) {
+                Ok(cgroup) => Some(Arc::new(SandboxCgroup(cgroup))),
+                Err(e) => {
+                    eprintln!("[{}] failed creating cgroup: {}", name, e);
+                    return Err(stream);
+                }
+            }
+        }
+        false => None,
+    };
+    // only HTTP/1.1 has trailers, and only a response with a body
+    let trailer_cgroup = match isolation_setting.server_timing
+        && req.version == "HTTP/1.1"
+        && req.method != HttpRequestMethod::HEAD
+    {
+        true => cgroup.as_ref().map(|cgroup| cgroup.0.path().to_path_buf()),
+        false => None,
+    };
+    let chunked = trailer_cgroup.is_some();
+    let seccomp = isolation_setting.seccomp.clone();
+    let capabilities: Vec<Capability> = isolation_setting.capabilities.iter().copied().collect();
+
+    // every process of the sandbox holds the write end, so reading
+    // the report only ends once the whole sandbox is gone. Other
+    // sandboxes cloned meanwhile close their copy in init.
+    let (report_r, report_w) = match unistd::pipe2(OFlag::O_CLOEXEC) {
+        Ok(pipe) => pipe,
+        Err(_) => return Err(stream),
+    };
+
+    // the sandbox gets its own copy of the fd table, the server keeps
+    // owning `stream` and closes its copy once the sandbox is started
     let fd = stream.as_raw_fd();
-    let parent_copy = stream.try_clone().unwrap();
-
-    let flags = libc::CLONE_NEWUSER
-        | libc::CLONE_NEWPID
-        | libc::CLONE_NEWNS
-        | libc::CLONE_NEWUTS
-        | libc::CLONE_NEWIPC
-        | libc::CLONE_NEWNET
-        | libc::CLONE_NEWCGROUP
-        | libc::SIGCHLD;
-
-    let ref mut init_stack = [0; STACK_SIZE];
+
+    let flags = isolation_setting.clone_flags();
+
+    let init_stack = &mut [0; STACK_SIZE];
     let init = move || {
-        let ref mut worker_stack = [0; STACK_SIZE];
+        signal::reset_default(&HOST_SIGNALS).unwrap();
+        // e.g. the listeners, connections and report pipes of other
+        // sandboxes, which would only end once this sandbox is gone too
+        close_fds_except(&[0, 1, 2, fd, report_w]);
+        let init_pid = unistd::getpid();
+        let claim = Claim::new().expect("Failed mapping answer claim");
+        // syscalls the seccomp filter failed, reported by the worker
+        let (violations_r, violations_w) = match &seccomp {
+            Some(_) => unistd::pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)
+                .map_or((None, None), |(r, w)| (Some(r), Some(w))),
+            None => (None, None),
+        };
+        let worker_stack = &mut [0; STACK_SIZE];
         let worker = move || {
+            // go down with init, which is not a given without a pid namespace
+            unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+            if unistd::getppid() != init_pid {
+                return 1; // init died before it could be noticed
+            }
+
             /* start in stopped state */
             signal::default_sigcont().unwrap();
             signal::sigsuspend();
@@ -100,55 +641,368 @@ pub fn isolate_req(
             /* whenever received SIGCONT */
             unistd::chroot(chrootfs.as_str()).unwrap();
             unistd::chdir("/").unwrap();
+            capability::drop_all_except(&capabilities).expect("Failed dropping capabilities");
+            // last before the handler, the worker itself is trusted
+            if let Some(profile) = &seccomp {
+                profile
+                    .install(violations_w)
+                    .expect("Failed installing seccomp filter");
+            }
 
-            let pres = handler(req);
-            stream.write(&pres.to_http_response()).unwrap();
-            stream.flush().unwrap();
+            let mut stream = unsafe { fs::File::from_raw_fd(fd) };
+            let method = req.method;
+            let called = monotonic_now();
+            // the connection ends with the sandbox
+            let pres = handler.handle(req).set_header("Connection", "close");
+            let chunked = chunked && pres.status().allows_body();
+            let res = match chunked {
+                true => pres.to_chunked_http_response(&["Server-Timing"]),
+                false => pres.to_http_response(method),
+            };
+            // init answers instead once the handler timed out
+            if !claim.take() {
+                return 1;
+            }
+            // e.g. the client went away, init leaves it be as the claim is taken
+            if stream.write_all(&res).and_then(|_| stream.flush()).is_err() {
+                report(report_w, pres.status().as_u16(), 0, Some(called));
+                return WRITE_FAILED;
+            }
+            report(report_w, pres.status().as_u16(), res.len(), Some(called));
+            // usage is only complete once the worker is gone
+            if chunked {
+                claim.leave_trailer();
+            }
             0 // exit
         };
         signal::block(&[nix::sys::signal::SIGCONT]);
-        match clone::clone_proc_newns(worker, worker_stack, libc::SIGCHLD) {
-            Ok(pid) => {
-                let umnt_pnts = isolation_setting.mount_all();
-                proxy_signal(pid, &cleanup_fs, umnt_pnts);
-            }
-            Err(_) => {}
-        };
+        // the host is done setting up, e.g. moved init into its cgroup
+        // which the worker then starts in
+        signal::sigwait(&[nix::sys::signal::SIGUSR1]).unwrap();
+        let worker = clone::clone_proc_newns(worker, worker_stack, libc::SIGCHLD);
+        if let Some(violations_w) = violations_w {
+            let _ = unistd::close(violations_w);
+        }
+        if let Ok(pid) = worker {
+            let umnt_pnts = isolation_setting.mount_all();
+            let fallback = Fallback {
+                fd,
+                claim,
+                report_fd: report_w,
+                failure: failure_response,
+                timeout: timeout.map(|timeout| (timeout, timeout_response)),
+                trailer_cgroup,
+            };
+            proxy_signal(pid, &cleanup_fs, umnt_pnts, fallback, violations_r);
+        }
 
         0 // exit
     };
 
     // mask SIGCONT of calling thread
     signal::block(&[nix::sys::signal::SIGUSR1]);
-    match clone::clone_proc_newns(init, init_stack, flags) {
-        Ok(pid) => {
+    let init = clone::clone_proc_newns_pidfd(init, init_stack, flags);
+    let _ = unistd::close(report_w);
+    match init {
+        Ok((pid, pidfd)) => {
+            let sandbox = Sandbox {
+                pid,
+                pidfd,
+                rootfs,
+                mount_targets,
+                report: Some(ReportReader {
+                    pipe: unsafe { fs::File::from_raw_fd(report_r) },
+                    cloned,
+                    cgroup: cgroup.clone(),
+                }),
+                cgroup,
+            };
             /* TODO: do stuff with pid (fs_prep?, idmap, gidmap, net) */
+            if let Some(cgroup) = &sandbox.cgroup {
+                if let Err(e) = cgroup.0.add_process(pid) {
+                    eprintln!(
+                        "[{}] failed joining cgroup: {}",
+                        cgroup.0.path().display(),
+                        e
+                    );
+                    let _ = sandbox.kill();
+                    let _ = waitpid(Pid::from_raw(pid), None);
+                    sandbox.cleanup();
+                    return Err(stream);
+                }
+            }
 
-            /* Fnished set up send SIGCONT */
+            /* Fnished set up send SIGUSR1 */
             unsafe { libc::kill(pid, libc::SIGUSR1) };
-            unsafe { libc::close(fd) }; // close dangle connection
-            Ok(())
+            Ok(sandbox)
+        }
+        Err(_) => {
+            let _ = unistd::close(report_r);
+            Err(stream)
+        }
+    }
+}
+
+/// Close every file descriptor of the calling process but `keep`
+pub(crate) fn close_fds_except(keep: &[RawFd]) {
+    let mut keep = keep.to_vec();
+    keep.sort_unstable();
+    keep.dedup();
+    let mut first = 0;
+    for next in keep.into_iter().map(|fd| fd as u32).chain(Some(u32::MAX)) {
+        if next > first {
+            close_range(first, next - 1);
         }
-        Err(_) => Err(parent_copy),
+        first = next.saturating_add(1);
     }
 }
 
-fn proxy_signal(pid: i32, rootfs: &str, umount_points: Vec<String>) {
+/// Close the file descriptors `first` to `last`, one at a time up to the
+/// limit of open files without `close_range`, which requires linux 5.9
+fn close_range(first: u32, last: u32) {
+    let res = unsafe { libc::syscall(libc::SYS_close_range, first, last, 0) };
+    if res == 0 {
+        return;
+    }
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+    let last = last.min(limit.rlim_cur.min(u32::MAX as u64) as u32);
+    for fd in first..=last {
+        unsafe { libc::close(fd as RawFd) };
+    }
+}
+
+/// Write the report read by `ReportReader::wait` to the pipe at `fd`,
+/// `called` is when the handler was called
+fn report(fd: RawFd, status: u16, bytes: usize, called: Option<Duration>) {
+    let called = called.map_or(0, |called| called.as_nanos());
+    let line = format!("{} {} {}\n", status, bytes, called);
+    let _ = unistd::write(fd, line.as_bytes());
+}
+
+/// Reading of the monotonic clock, which is shared by every process of
+/// the host unlike `Instant` that can't be passed between processes
+fn monotonic_now() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// Right to answer the request, taken by the worker before writing its
+/// response or by init before answering in its place, so that only one
+/// of them does. Lives in memory init shares with the worker.
+#[derive(Clone, Copy)]
+struct Claim(*const ClaimState);
+
+/// Shared by init and the worker
+#[repr(C)]
+struct ClaimState {
+    taken: AtomicBool,
+    /// the worker left the `Server-Timing` trailer of its chunked
+    /// response to init
+    trailer_pending: AtomicBool,
+}
+
+impl Claim {
+    fn new() -> Result<Claim, nix::Error> {
+        let claim = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                std::mem::size_of::<ClaimState>(),
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED | MapFlags::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        }?;
+        // zeroed, which is `false`
+        Ok(Claim(claim as *const ClaimState))
+    }
+
+    /// whether the caller took the claim, only ever true for one of them
+    fn take(&self) -> bool {
+        !unsafe { &*self.0 }.taken.swap(true, Ordering::SeqCst)
+    }
+
+    /// Leave the trailer to init, by the worker once it answered
+    fn leave_trailer(&self) {
+        unsafe { &*self.0 }
+            .trailer_pending
+            .store(true, Ordering::SeqCst)
+    }
+
+    fn trailer_pending(&self) -> bool {
+        unsafe { &*self.0 }.trailer_pending.load(Ordering::SeqCst)
+    }
+}
+
+/// How the init process answers in place of a worker that couldn't
+struct Fallback {
+    /// socket the worker answers on
+    fd: RawFd,
+    /// taken by whichever of init and the worker answers first
+    claim: Claim,
+    /// pipe the sandbox reports on
+    report_fd: RawFd,
+    /// `500` sent when the worker dies without answering, e.g. on a panic
+    failure: Vec<u8>,
+    /// handler timeout and the `504` sent once it expired
+    timeout: Option<(Duration, Vec<u8>)>,
+    /// cgroup of the sandbox when the worker leaves ending its chunked
+    /// response with a `Server-Timing` trailer to init
+    trailer_cgroup: Option<PathBuf>,
+}
+
+impl Fallback {
+    /// Write `response` for the worker and report it, unless the worker
+    /// already started answering
+    fn answer(&self, response: &[u8], status: StatusCode) {
+        if !self.claim.take() {
+            return;
+        }
+        let mut stream = ManuallyDrop::new(unsafe { fs::File::from_raw_fd(self.fd) });
+        let _ = stream.write_all(response);
+        report(self.report_fd, status.as_u16(), response.len(), None);
+    }
+
+    /// End the chunked response of the worker with the resources
+    /// the sandbox used so far, and report the bytes added
+    fn send_trailer(&self) {
+        let usage = self
+            .trailer_cgroup
+            .as_ref()
+            .and_then(|path| Cgroup::open(path).usage().ok());
+        let trailers = match &usage {
+            Some(usage) => vec![("Server-Timing", server_timing(usage))],
+            None => Vec::new(),
+        };
+        let chunk = last_chunk(&trailers);
+        let mut stream = ManuallyDrop::new(unsafe { fs::File::from_raw_fd(self.fd) });
+        if stream.write_all(&chunk).is_ok() {
+            let _ = unistd::write(self.report_fd, format!("+{}\n", chunk.len()).as_bytes());
+        }
+    }
+}
+
+/// Report on stderr the syscalls `denied` to the handler by its seccomp
+/// filter, each once with how many times it was denied
+fn report_violations(rootfs: &str, denied: &[libc::c_long]) {
+    let mut counts: Vec<(libc::c_long, usize)> = Vec::new();
+    for nr in denied {
+        match counts.iter_mut().find(|(counted, _)| counted == nr) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((*nr, 1)),
+        }
+    }
+    if counts.is_empty() {
+        return;
+    }
+    let syscalls: Vec<String> = counts
+        .iter()
+        .map(|(nr, count)| {
+            let name = seccomp::syscall_name(*nr).map_or_else(|| nr.to_string(), str::to_string);
+            match count {
+                1 => name,
+                _ => format!("{} x{}", name, count),
+            }
+        })
+        .collect();
+    eprintln!(
+        "[{}] seccomp filter denied the handler {}",
+        rootfs,
+        syscalls.join(", ")
+    );
+}
+
+/// `Server-Timing` value describing `usage`, CPU time in milliseconds
+/// and memory and io in bytes
+fn server_timing(usage: &Usage) -> String {
+    let mut metrics = vec![format!("cpu;dur={:.3}", usage.cpu.as_secs_f64() * 1000.0)];
+    let bytes = [
+        ("memory-peak", usage.memory_peak),
+        ("io-read", usage.io_read),
+        ("io-write", usage.io_write),
+    ];
+    for (name, value) in bytes.iter() {
+        if let Some(value) = value {
+            metrics.push(format!("{};desc=\"{}\"", name, value));
+        }
+    }
+    metrics.join(", ")
+}
+
+/// Supervise the worker from the init process: start it, arm the handler
+/// timeout and clean up when it exits. A worker that exits
+/// abnormally is answered for with `500`, a kill by its seccomp filter
+/// and the syscalls it failed, read from `violations`, are also reported
+/// on stderr. One still running once the handler timeout expired is
+/// killed and answered for with `504`, unless it started answering.
+fn proxy_signal(
+    pid: i32,
+    rootfs: &str,
+    umount_points: Vec<String>,
+    fallback: Fallback,
+    violations: Option<RawFd>,
+) {
     signal::set_sa_nocldstop().expect("Failed installing SIGCHLD handler");
-    let sigs = [libc::SIGUSR1, libc::SIGCHLD];
-    let mut siginfo = sighook::iterator::Signals::new(&sigs).unwrap(); // safe unwrap
+    let sigs = [libc::SIGCHLD, libc::SIGALRM];
+    let mut siginfo = sighook::iterator::Signals::new(sigs).unwrap(); // safe unwrap
+    let worker = Pid::from_raw(pid);
+    // exit status of the worker when reaped before its SIGCHLD was handled
+    let mut reaped = None;
 
-    signal::unblock(&[nix::sys::signal::SIGUSR1]);
+    unsafe { libc::kill(pid, libc::SIGCONT) };
+    if let Some((timeout, _)) = &fallback.timeout {
+        signal::set_alarm(*timeout).expect("Failed arming handler timeout");
+    }
     for sig in siginfo.forever() {
         match sig {
             libc::SIGCHLD => {
+                let status = match waitpid(worker, Some(WaitPidFlag::WNOHANG)) {
+                    // another process of the sandbox, reparented to init
+                    Ok(WaitStatus::StillAlive) => continue,
+                    Ok(status) => Some(status),
+                    Err(_) => reaped.take(),
+                };
+                if let Some(WaitStatus::Signaled(_, Signal::SIGSYS, _)) = status {
+                    eprintln!("[{}] handler killed by its seccomp filter", rootfs);
+                }
+                if let Some(denied) = violations.map(seccomp::read_violations) {
+                    report_violations(rootfs, &denied);
+                }
+                let clean_exit = matches!(status, None | Some(WaitStatus::Exited(_, 0)));
+                if clean_exit && fallback.claim.trailer_pending() {
+                    fallback.send_trailer();
+                } else if !clean_exit {
+                    fallback.answer(&fallback.failure, StatusCode::INTERNAL_SERVER_ERROR);
+                }
+
+                // like Sandbox::cleanup, best effort as init is on its way out
                 for mnt_points in umount_points {
-                    umount(mnt_points.as_str()).unwrap();
+                    let _ = umount2(mnt_points.as_str(), MntFlags::MNT_DETACH);
                 }
-                fs::remove_dir_all(rootfs).unwrap();
+                let _ = fs::remove_dir_all(rootfs);
                 unsafe { libc::exit(0) }
             }
-            libc::SIGUSR1 => unsafe { libc::kill(pid, libc::SIGCONT) },
+            libc::SIGALRM => {
+                let (_, response) = fallback.timeout.as_ref().unwrap(); // safe unwrap
+
+                // the worker may be done already with its SIGCHLD still pending
+                match waitpid(worker, Some(WaitPidFlag::WNOHANG)) {
+                    Ok(WaitStatus::StillAlive) => {
+                        unsafe { libc::kill(pid, libc::SIGKILL) };
+                        fallback.answer(response, StatusCode::GATEWAY_TIMEOUT);
+                    }
+                    Ok(status) => reaped = Some(status),
+                    Err(_) => {}
+                }
+            }
             _ => unreachable!(),
         };
     }
@@ -1,8 +1,25 @@
 pub use libpotato::libc;
 pub use libpotato::nix;
 
+pub mod access_log;
+pub mod async_server;
+pub mod config;
+pub mod handler;
+pub mod header;
 pub mod isolation;
+pub mod listener;
+pub mod metrics;
+pub mod middleware;
+pub mod pool;
 pub mod prep;
 pub mod request;
 pub mod response;
+pub mod router;
 pub mod server;
+pub mod shutdown;
+pub mod state;
+pub mod static_files;
+pub mod status;
+pub mod stream;
+pub mod tls;
+pub mod uri;
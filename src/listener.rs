@@ -0,0 +1,174 @@
+use crate::libc;
+use crate::stream::Stream;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// First file descriptor passed by systemd socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Pause of the accept loops after an error not tied to a single
+/// connection, e.g. EMFILE, which would fail again right away
+pub(crate) const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Where the server accepts connections
+#[derive(Clone, Debug)]
+pub enum ListenAddr {
+    /// TCP on an IPv4 or IPv6 address
+    Tcp(SocketAddr),
+    /// Unix domain socket created at `path` with permissions `mode`
+    Unix { path: PathBuf, mode: u32 },
+    /// Listening socket inherited from the parent, e.g. from systemd
+    Fd(RawFd),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+            ListenAddr::Fd(fd) => write!(f, "fd:{}", fd),
+        }
+    }
+}
+
+/// Bound listening socket
+pub enum Listener {
+    Tcp(TcpListener),
+    /// path is set when the socket file was created by us and is removed on drop
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    pub fn bind(addr: &ListenAddr) -> io::Result<Listener> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            ListenAddr::Unix { path, mode } => {
+                // a socket left over by a previous run would fail the bind
+                if let Ok(meta) = fs::symlink_metadata(path) {
+                    if meta.file_type().is_socket() {
+                        fs::remove_file(path)?;
+                    }
+                }
+                let listener = UnixListener::bind(path)?;
+                fs::set_permissions(path, fs::Permissions::from_mode(*mode))?;
+                Ok(Listener::Unix(listener, Some(path.clone())))
+            }
+            ListenAddr::Fd(fd) => match socket_domain(*fd)? {
+                libc::AF_UNIX => Ok(Listener::Unix(
+                    unsafe { UnixListener::from_raw_fd(*fd) },
+                    None,
+                )),
+                _ => Ok(Listener::Tcp(unsafe { TcpListener::from_raw_fd(*fd) })),
+            },
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            Listener::Unix(listener, _) => {
+                listener.accept().map(|(stream, _)| Stream::Unix(stream))
+            }
+        }
+    }
+}
+
+/// Whether the accept error `e` only concerns the connection being
+/// accepted, so that the next accept can follow without a pause
+pub(crate) fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+    )
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "fd:{}", listener.as_raw_fd()),
+            },
+            Listener::Unix(listener, _) => match listener.local_addr() {
+                Ok(addr) => match addr.as_pathname() {
+                    Some(path) => write!(f, "unix:{}", path.display()),
+                    None => write!(f, "unix:fd:{}", listener.as_raw_fd()),
+                },
+                Err(_) => write!(f, "unix:fd:{}", listener.as_raw_fd()),
+            },
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some(path)) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// File descriptors of the listening sockets passed by systemd socket
+/// activation, see
+/// `sd_listen_fds(3)`. Empty when the process was not socket activated.
+/// The activation variables are removed from the environment so that
+/// they are not picked up again.
+pub fn systemd_listen_fds() -> Vec<RawFd> {
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok());
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    match (pid, count) {
+        (Some(pid), Some(count)) if pid == std::process::id() => (SD_LISTEN_FDS_START
+            ..SD_LISTEN_FDS_START + count)
+            .inspect(|fd| {
+                unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn socket_domain(fd: RawFd) -> io::Result<libc::c_int> {
+    let mut domain: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_DOMAIN,
+            &mut domain as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    match ret {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(domain),
+    }
+}
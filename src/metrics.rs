@@ -0,0 +1,355 @@
+use crate::access_log::LogEntry;
+use crate::pool::PoolMetrics;
+use crate::request::HttpRequestMethod;
+use crate::response::PotatoResponse;
+use crate::status::StatusCode;
+use libpotato::cgroup::Usage;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds in seconds of the histogram buckets
+const BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Upper bounds in bytes of the memory histogram buckets, 1MiB to 4GiB
+const BYTE_BUCKETS: [f64; 7] = [
+    1048576.0,
+    4194304.0,
+    16777216.0,
+    67108864.0,
+    268435456.0,
+    1073741824.0,
+    4294967296.0,
+];
+
+/// Route label of requests no route matched, e.g. served by the default handler
+const UNMATCHED: &str = "unmatched";
+
+struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new(&BUCKETS)
+    }
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            buckets: vec![0; bounds.len()],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, duration: Duration) {
+        self.observe_value(duration.as_secs_f64());
+    }
+
+    fn observe_value(&mut self, value: f64) {
+        for (bucket, le) in self.buckets.iter_mut().zip(self.bounds) {
+            if value <= *le {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (count, le) in self.buckets.iter().zip(self.bounds) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, sep, le, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, sep, self.count
+        );
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+/// Requests served by one route for one method
+#[derive(Default)]
+struct RouteStats {
+    by_status: HashMap<u16, u64>,
+    latency: Histogram,
+    /// resources used by its sandboxes, `None` until one was accounted
+    usage: Option<UsageStats>,
+}
+
+/// Resources used by the sandboxes of one route
+struct UsageStats {
+    cpu: Duration,
+    io_read: u64,
+    io_write: u64,
+    memory_peak: Histogram,
+}
+
+impl UsageStats {
+    fn new() -> UsageStats {
+        UsageStats {
+            cpu: Duration::ZERO,
+            io_read: 0,
+            io_write: 0,
+            memory_peak: Histogram::new(&BYTE_BUCKETS),
+        }
+    }
+
+    fn observe(&mut self, usage: &Usage) {
+        self.cpu += usage.cpu;
+        self.io_read += usage.io_read.unwrap_or(0);
+        self.io_write += usage.io_write.unwrap_or(0);
+        if let Some(peak) = usage.memory_peak {
+            self.memory_peak.observe_value(peak as f64);
+        }
+    }
+}
+
+/// Registry of the server metrics, rendered in the Prometheus text format.
+///
+/// Request latency is counted from the moment the request was received to
+/// the moment the response was written, or reported by the sandbox.
+#[derive(Default)]
+pub struct Metrics {
+    routes: Mutex<HashMap<(HttpRequestMethod, String), RouteStats>>,
+    sandboxes: AtomicI64,
+    clone_failures: AtomicU64,
+    sandbox_setup: Mutex<Histogram>,
+    sandbox_teardown: Mutex<Histogram>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Count a request that has been answered
+    pub fn observe(&self, entry: &LogEntry) {
+        let route = entry.route.as_deref().unwrap_or(UNMATCHED).to_string();
+        let mut routes = self.routes.lock().unwrap();
+        let stats = routes.entry((entry.method, route)).or_default();
+        *stats.by_status.entry(entry.status).or_default() += 1;
+        stats.latency.observe(entry.duration);
+        if let Some(usage) = &entry.usage {
+            stats
+                .usage
+                .get_or_insert_with(UsageStats::new)
+                .observe(usage);
+        }
+    }
+
+    /// A sandbox was started
+    pub fn sandbox_started(&self) {
+        self.sandboxes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A sandbox exited
+    pub fn sandbox_exited(&self) {
+        self.sandboxes.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Cloning the init process of a sandbox failed
+    pub fn clone_failed(&self) {
+        self.clone_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Time from cloning the sandbox to its handler being called
+    pub fn observe_setup(&self, duration: Duration) {
+        self.sandbox_setup.lock().unwrap().observe(duration);
+    }
+
+    /// Time from the sandbox answering to all of its processes being gone
+    pub fn observe_teardown(&self, duration: Duration) {
+        self.sandbox_teardown.lock().unwrap().observe(duration);
+    }
+
+    pub fn sandboxes_in_flight(&self) -> i64 {
+        self.sandboxes.load(Ordering::Relaxed)
+    }
+
+    pub fn clone_failures(&self) -> u64 {
+        self.clone_failures.load(Ordering::Relaxed)
+    }
+
+    /// Render every metric, along with the load of the worker `pool`
+    pub fn render(&self, pool: &PoolMetrics) -> String {
+        let mut out = String::new();
+        {
+            let routes = self.routes.lock().unwrap();
+            let mut keys: Vec<&(HttpRequestMethod, String)> = routes.keys().collect();
+            keys.sort();
+
+            header(
+                &mut out,
+                "potato_requests_total",
+                "counter",
+                "Requests answered",
+            );
+            for key in &keys {
+                let labels = route_labels(key);
+                let mut statuses: Vec<(&u16, &u64)> = routes[*key].by_status.iter().collect();
+                statuses.sort();
+                for (status, count) in statuses {
+                    let _ = writeln!(
+                        out,
+                        "potato_requests_total{{{},status=\"{}\"}} {}",
+                        labels, status, count
+                    );
+                }
+            }
+
+            let name = "potato_request_duration_seconds";
+            header(&mut out, name, "histogram", "Time taken to answer requests");
+            for key in &keys {
+                routes[*key]
+                    .latency
+                    .render(&mut out, name, &route_labels(key));
+            }
+
+            let accounted: Vec<(String, &UsageStats)> = keys
+                .iter()
+                .filter_map(|key| Some((route_labels(key), routes[*key].usage.as_ref()?)))
+                .collect();
+            let name = "potato_sandbox_cpu_seconds_total";
+            header(&mut out, name, "counter", "CPU time used by sandboxes");
+            for (labels, usage) in &accounted {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, usage.cpu.as_secs_f64());
+            }
+
+            let name = "potato_sandbox_io_read_bytes_total";
+            header(
+                &mut out,
+                name,
+                "counter",
+                "Bytes read from block devices by sandboxes",
+            );
+            for (labels, usage) in &accounted {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, usage.io_read);
+            }
+
+            let name = "potato_sandbox_io_write_bytes_total";
+            header(
+                &mut out,
+                name,
+                "counter",
+                "Bytes written to block devices by sandboxes",
+            );
+            for (labels, usage) in &accounted {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, usage.io_write);
+            }
+
+            let name = "potato_sandbox_memory_peak_bytes";
+            header(&mut out, name, "histogram", "Peak memory use of sandboxes");
+            for (labels, usage) in &accounted {
+                usage.memory_peak.render(&mut out, name, labels);
+            }
+        }
+
+        let name = "potato_sandboxes_in_flight";
+        header(&mut out, name, "gauge", "Sandboxes currently running");
+        let _ = writeln!(out, "{} {}", name, self.sandboxes_in_flight());
+
+        let name = "potato_sandbox_clone_failures_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Sandboxes that failed to be cloned",
+        );
+        let _ = writeln!(out, "{} {}", name, self.clone_failures());
+
+        let name = "potato_sandbox_setup_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Time from clone to the handler being called",
+        );
+        self.sandbox_setup
+            .lock()
+            .unwrap()
+            .render(&mut out, name, "");
+
+        let name = "potato_sandbox_teardown_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Time from the response to the sandbox exiting",
+        );
+        self.sandbox_teardown
+            .lock()
+            .unwrap()
+            .render(&mut out, name, "");
+
+        let name = "potato_pool_active_workers";
+        header(&mut out, name, "gauge", "Pool workers serving a connection");
+        let _ = writeln!(out, "{} {}", name, pool.active());
+
+        let name = "potato_pool_queued_connections";
+        header(
+            &mut out,
+            name,
+            "gauge",
+            "Connections waiting for a pool worker",
+        );
+        let _ = writeln!(out, "{} {}", name, pool.queued());
+
+        let name = "potato_pool_rejected_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Connections turned away with 503",
+        );
+        let _ = writeln!(out, "{} {}", name, pool.rejected());
+
+        out
+    }
+
+    /// Response to a scrape of the metrics endpoint
+    pub(crate) fn response(&self, pool: &PoolMetrics) -> PotatoResponse {
+        PotatoResponse::new()
+            .set_status(StatusCode::OK)
+            .set_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+            .add_body(self.render(pool).into_bytes())
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn route_labels((method, route): &(HttpRequestMethod, String)) -> String {
+    format!("method=\"{}\",route=\"{}\"", method, escape_label(route))
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
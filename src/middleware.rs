@@ -0,0 +1,78 @@
+use crate::handler::Handler;
+use crate::request::PotatoRequest;
+use crate::response::PotatoResponse;
+use crate::server::PotatoRequestHandler;
+use std::sync::Arc;
+
+/// Rest of the chain a `Middleware` hands the request to
+pub type Next<'a> = &'a dyn Fn(PotatoRequest) -> PotatoResponse;
+
+/// Logic wrapped around every handler invocation.
+///
+/// A middleware runs wherever the handler runs: in the server process for
+/// normal routes and inside the sandbox, after `chroot`, for isolated ones.
+/// It may alter the request before calling `next`, alter the response
+/// `next` returns, or skip `next` and answer on its own.
+/// Middlewares are called in the order they were added, the first one
+/// being the outermost.
+pub trait Middleware: Send + Sync + 'static {
+    fn call(&self, req: PotatoRequest, next: Next) -> PotatoResponse;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(PotatoRequest, Next) -> PotatoResponse + Send + Sync + 'static,
+{
+    fn call(&self, req: PotatoRequest, next: Next) -> PotatoResponse {
+        self(req, next)
+    }
+}
+
+/// Check run in the server process once a request has been routed and
+/// before it is dispatched, for isolated routes that is before any sandbox
+/// is set up. Returning a response short-circuits the request.
+/// Filters are called in the order they were added.
+pub trait Filter: Send + Sync + 'static {
+    fn filter(&self, req: &mut PotatoRequest) -> Option<PotatoResponse>;
+}
+
+impl<F> Filter for F
+where
+    F: Fn(&mut PotatoRequest) -> Option<PotatoResponse> + Send + Sync + 'static,
+{
+    fn filter(&self, req: &mut PotatoRequest) -> Option<PotatoResponse> {
+        self(req)
+    }
+}
+
+/// A handler wrapped in middlewares, itself usable as a handler
+pub struct Chain {
+    middlewares: Vec<Arc<dyn Middleware>>,
+    handler: PotatoRequestHandler,
+}
+
+impl Chain {
+    pub fn new(middlewares: Vec<Arc<dyn Middleware>>, handler: PotatoRequestHandler) -> Chain {
+        Chain {
+            middlewares,
+            handler,
+        }
+    }
+}
+
+impl Handler for Chain {
+    fn handle(&self, req: PotatoRequest) -> PotatoResponse {
+        run(&self.middlewares, &*self.handler, req)
+    }
+}
+
+fn run(
+    middlewares: &[Arc<dyn Middleware>],
+    handler: &dyn Handler,
+    req: PotatoRequest,
+) -> PotatoResponse {
+    match middlewares.split_first() {
+        Some((first, rest)) => first.call(req, &|req| run(rest, handler, req)),
+        None => handler.handle(req),
+    }
+}
@@ -0,0 +1,111 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Counters describing the load of a `WorkerPool`
+#[derive(Default)]
+pub struct PoolMetrics {
+    active: AtomicUsize,
+    queued: AtomicUsize,
+    rejected: AtomicUsize,
+    completed: AtomicUsize,
+}
+
+impl PoolMetrics {
+    pub fn new() -> PoolMetrics {
+        PoolMetrics::default()
+    }
+
+    /// Jobs being run right now
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Jobs waiting for a free worker
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Jobs turned away because the queue was full
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Jobs run to completion, including those that panicked
+    pub fn completed(&self) -> usize {
+        self.completed.load(Ordering::Relaxed)
+    }
+}
+
+/// Fixed set of threads running `job` on items pulled off a bounded queue
+pub struct WorkerPool<T> {
+    sender: SyncSender<T>,
+    metrics: Arc<PoolMetrics>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    /// Spawn `workers` threads sharing a queue of `queue_size` pending items.
+    /// A `queue_size` of 0 only accepts items when a worker is idle.
+    pub fn new<F>(
+        workers: usize,
+        queue_size: usize,
+        metrics: Arc<PoolMetrics>,
+        job: F,
+    ) -> WorkerPool<T>
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        assert!(workers > 0, "worker pool needs at least 1 worker");
+        let (sender, receiver) = mpsc::sync_channel::<T>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let job = Arc::new(job);
+
+        for i in 0..workers {
+            let receiver = Arc::clone(&receiver);
+            let metrics = Arc::clone(&metrics);
+            let job = Arc::clone(&job);
+            thread::Builder::new()
+                .name(format!("potato-worker-{}", i))
+                .spawn(move || work(receiver, metrics, &*job))
+                .expect("Failed to spawn worker thread");
+        }
+
+        WorkerPool { sender, metrics }
+    }
+
+    /// Queue `item`, or give it back when every worker is busy and the queue is full
+    pub fn try_execute(&self, item: T) -> Result<(), T> {
+        // count before sending so a worker never sees the counter go below zero
+        self.metrics.queued.fetch_add(1, Ordering::Relaxed);
+        match self.sender.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item)) | Err(TrySendError::Disconnected(item)) => {
+                self.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(item)
+            }
+        }
+    }
+
+    pub fn metrics(&self) -> &Arc<PoolMetrics> {
+        &self.metrics
+    }
+}
+
+fn work<T>(receiver: Arc<Mutex<Receiver<T>>>, metrics: Arc<PoolMetrics>, job: &dyn Fn(T)) {
+    loop {
+        // the lock is released as soon as an item is received
+        let item = match receiver.lock().unwrap().recv() {
+            Ok(item) => item,
+            Err(_) => return,
+        };
+        metrics.queued.fetch_sub(1, Ordering::Relaxed);
+        metrics.active.fetch_add(1, Ordering::Relaxed);
+        // a panicking job must not take its worker down with it
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| job(item)));
+        metrics.active.fetch_sub(1, Ordering::Relaxed);
+        metrics.completed.fetch_add(1, Ordering::Relaxed);
+    }
+}
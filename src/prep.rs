@@ -1,18 +1,15 @@
 use std::fs;
+use std::net::Ipv4Addr;
 
 pub fn fs_prep(runtime_dir: &str) -> String {
     let mut list: Vec<usize> = fs::read_dir(runtime_dir)
         .unwrap()
-        .filter(|result| result.is_ok())
-        .map(|result| result.unwrap())
+        .flatten()
         .filter(|dir_entry| dir_entry.file_type().is_ok())
         .filter(|dir_entry| dir_entry.file_type().unwrap().is_dir())
         .map(|dir_entry| dir_entry.file_name())
-        .map(|file_name| file_name.into_string())
-        .filter(|result| result.is_ok())
-        .map(|string| string.unwrap().parse::<usize>())
-        .filter(|result| result.is_ok())
-        .map(|result| result.unwrap())
+        .flat_map(|file_name| file_name.into_string())
+        .flat_map(|string| string.parse::<usize>())
         .collect();
 
     list.sort_unstable();
@@ -31,4 +28,14 @@ pub fn fs_prep(runtime_dir: &str) -> String {
     req_dir.to_string()
 }
 
-pub fn net_prep(veth_name: &str, pid: i32) {}
+/// Whether `subnet` is written like `10.0.0.0/24`
+pub(crate) fn is_ipv4_cidr(subnet: &str) -> bool {
+    match subnet.split_once('/') {
+        Some((addr, prefix)) => {
+            addr.parse::<Ipv4Addr>().is_ok() && matches!(prefix.parse::<u8>(), Ok(0..=32))
+        }
+        None => false,
+    }
+}
+
+pub fn net_prep(_veth_name: &str, _pid: i32) {}
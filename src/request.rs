@@ -1,8 +1,17 @@
+use crate::header::{self, HeaderMap};
+use crate::state::AppState;
+use crate::status::StatusCode;
+use crate::stream::Stream;
+use crate::uri;
+use std::any::Any;
 use std::collections::HashMap;
 use std::fmt;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[allow(dead_code)]
-#[derive(Eq, PartialEq, Hash, Clone, Copy)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy)]
 pub enum HttpRequestMethod {
     GET,
     HEAD,
@@ -16,8 +25,8 @@ pub enum HttpRequestMethod {
 }
 
 impl HttpRequestMethod {
-    fn to_string(&self) -> String {
-        let name = match self {
+    fn as_str(self) -> &'static str {
+        match self {
             HttpRequestMethod::GET => "GET",
             HttpRequestMethod::HEAD => "HEAD",
             HttpRequestMethod::POST => "POST",
@@ -27,11 +36,10 @@ impl HttpRequestMethod {
             HttpRequestMethod::OPTIONS => "OPTIONS",
             HttpRequestMethod::TRACE => "TRACE",
             HttpRequestMethod::PATCH => "PATCH",
-        };
-        name.to_string()
+        }
     }
 
-    fn from_str(s: &str) -> Option<HttpRequestMethod> {
+    pub(crate) fn from_str(s: &str) -> Option<HttpRequestMethod> {
         match s {
             "GET" => Some(HttpRequestMethod::GET),
             "HEAD" => Some(HttpRequestMethod::HEAD),
@@ -49,15 +57,31 @@ impl HttpRequestMethod {
 
 impl fmt::Display for HttpRequestMethod {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.to_string())
+        write!(f, "{}", self.as_str())
     }
 }
 
 pub struct PotatoRequest {
     pub method: HttpRequestMethod,
+    /// percent-decoded path of the request target with dot segments resolved
     pub path: String,
-    pub headers: HashMap<String, String>,
+    /// request target exactly as received, query string included
+    pub target: String,
+    /// decoded query string parameters, each key keeps all of its values
+    pub query: HashMap<String, Vec<String>>,
+    /// protocol version from the request line e.g. `HTTP/1.1`
+    pub version: String,
+    /// values captured by `:param` and `*wildcard` segments of the matched route
+    pub params: HashMap<String, String>,
+    /// path of the matched route as registered, e.g. `/users/:id`,
+    /// `None` until routed and for requests no route matched
+    pub route: Option<String>,
+    pub headers: HeaderMap,
     pub body: Option<Vec<u8>>,
+    /// trailer fields sent after a chunked body
+    pub trailers: HeaderMap,
+    /// application state registered on the server
+    pub state: AppState,
 }
 
 impl PotatoRequest {
@@ -65,49 +89,800 @@ impl PotatoRequest {
         PotatoRequest {
             method,
             path: path.to_string(),
-            headers: HashMap::new(),
-            body: body,
+            target: path.to_string(),
+            query: HashMap::new(),
+            version: "HTTP/1.1".to_string(),
+            params: HashMap::new(),
+            route: None,
+            headers: HeaderMap::new(),
+            body,
+            trailers: HeaderMap::new(),
+            state: AppState::new(),
         }
     }
 
-    pub fn from_raw_req(raw: &[u8]) -> PotatoRequest {
-        let index = raw
-            .windows(2)
-            .enumerate()
-            .find(|(_, w)| matches!(*w, b"\r\n"))
-            .map(|(i, _)| i)
-            .unwrap();
+    /// Application state of type `T` registered on the server
+    pub fn state<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        self.state.get::<T>()
+    }
+
+    /// First value of query parameter `key`
+    pub fn query_param(&self, key: &str) -> Option<&str> {
+        self.query
+            .get(key)
+            .and_then(|values| values.first())
+            .map(|value| value.as_str())
+    }
+
+    /// Whether the client wants the connection kept open after this request.
+    /// HTTP/1.1 defaults to persistent connections, HTTP/1.0 has to ask for it.
+    pub fn keep_alive(&self) -> bool {
+        let has_option = |option: &str| {
+            self.headers
+                .get_all("Connection")
+                .flat_map(|value| value.split(','))
+                .any(|opt| opt.trim().eq_ignore_ascii_case(option))
+        };
+
+        if self.version == "HTTP/1.0" {
+            has_option("keep-alive")
+        } else {
+            !has_option("close")
+        }
+    }
+}
+
+/// Upper bounds applied while reading a request off the wire
+#[derive(Clone, Copy)]
+pub struct RequestLimits {
+    /// maximum size of request line and headers including the blank line
+    pub max_header_size: usize,
+    /// maximum size of the request body
+    pub max_body_size: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> RequestLimits {
+        RequestLimits {
+            max_header_size: 8 * 1024,
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+/// How long reading a request may take
+#[derive(Clone, Copy)]
+pub struct ReadTimeouts {
+    /// wait for the first byte of a request
+    pub idle: Duration,
+    /// receive the request line and headers, counted from their first byte
+    pub header: Duration,
+    /// receive the body, counted from the end of the headers
+    pub body: Duration,
+}
+
+/// What a `RequestParser` is waiting for
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ReadStage {
+    Idle,
+    Head,
+    Body,
+}
+
+/// Deadline of the stage a request is being read at, restarted on each stage
+pub(crate) struct ReadDeadline {
+    timeouts: ReadTimeouts,
+    stage: ReadStage,
+    since: Instant,
+}
 
-        let head = String::from_utf8_lossy(&raw[..index]);
-        let (raw_method, back) = head.split_at(head.find("/").unwrap());
-        let (raw_path, _) = back.split_at(back.find("H").unwrap());
+impl ReadDeadline {
+    pub(crate) fn new(timeouts: ReadTimeouts) -> ReadDeadline {
+        ReadDeadline {
+            timeouts,
+            stage: ReadStage::Idle,
+            since: Instant::now(),
+        }
+    }
 
-        let method = match HttpRequestMethod::from_str(raw_method.trim()) {
-            std::option::Option::Some(method) => method,
-            std::option::Option::None => HttpRequestMethod::OPTIONS,
+    /// How long the next read may wait, fails once the deadline has passed.
+    /// Running out of time before the first byte only closes the connection.
+    pub(crate) fn remaining(&mut self, stage: ReadStage) -> Result<Duration, RequestError> {
+        if stage != self.stage {
+            self.stage = stage;
+            self.since = Instant::now();
+        }
+        let timeout = match stage {
+            ReadStage::Idle => self.timeouts.idle,
+            ReadStage::Head => self.timeouts.header,
+            ReadStage::Body => self.timeouts.body,
         };
-        let path = raw_path.trim();
+        match timeout.checked_sub(self.since.elapsed()) {
+            Some(remaining) if !remaining.is_zero() => Ok(remaining),
+            _ if stage == ReadStage::Idle => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+            _ => Err(RequestError::Timeout),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// peer closed the connection before sending any byte of a request
+    ConnectionClosed,
+    HeaderTooLarge,
+    BodyTooLarge,
+    /// transfer coding other than `chunked` was applied to the body
+    UnsupportedTransferCoding,
+    BadRequest(&'static str),
+    /// request not received in time, see `ReadTimeouts`
+    Timeout,
+    Io(io::Error),
+}
+
+impl RequestError {
+    /// Status that should be sent back to the client for this error.
+    /// `None` when the connection is unusable and should just be dropped.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            RequestError::HeaderTooLarge => Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            RequestError::BodyTooLarge => Some(StatusCode::PAYLOAD_TOO_LARGE),
+            RequestError::UnsupportedTransferCoding => Some(StatusCode::NOT_IMPLEMENTED),
+            RequestError::BadRequest(_) => Some(StatusCode::BAD_REQUEST),
+            RequestError::Timeout => Some(StatusCode::REQUEST_TIMEOUT),
+            RequestError::ConnectionClosed | RequestError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::ConnectionClosed => write!(f, "Socket closed"),
+            RequestError::HeaderTooLarge => write!(f, "Request header too large"),
+            RequestError::BodyTooLarge => write!(f, "Request body too large"),
+            RequestError::UnsupportedTransferCoding => write!(f, "Unsupported transfer coding"),
+            RequestError::BadRequest(reason) => write!(f, "Bad request: {}", reason),
+            RequestError::Timeout => write!(f, "Request timeout"),
+            RequestError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> RequestError {
+        RequestError::Io(e)
+    }
+}
+
+/// How the end of a request body is determined
+enum BodyFraming {
+    Length(usize),
+    Chunked(ChunkedBody),
+}
 
-        let bindex = raw
+/// Progress of decoding a `Transfer-Encoding: chunked` body
+struct ChunkedBody {
+    state: ChunkState,
+    /// offset into the parser buffer of the next undecoded byte
+    pos: usize,
+    decoded: Vec<u8>,
+    trailers: HeaderMap,
+    trailer_size: usize,
+}
+
+enum ChunkState {
+    Size,
+    Data(usize),
+    Trailer,
+    Done,
+}
+
+/// Longest chunk-size line, extensions included, accepted before giving up
+const MAX_CHUNK_LINE: usize = 1024;
+
+/// Request line and headers of a request whose body is still being received
+struct RequestHead {
+    method: HttpRequestMethod,
+    target: String,
+    path: String,
+    query: HashMap<String, Vec<String>>,
+    version: String,
+    headers: HeaderMap,
+    framing: BodyFraming,
+}
+
+/// Incremental HTTP/1.1 request parser.
+///
+/// Bytes are pushed in with `feed` as they arrive and `parse` is called
+/// until it yields a request. Bytes following a complete request are kept
+/// in the buffer so the next call to `parse` can pick them up.
+pub struct RequestParser {
+    limits: RequestLimits,
+    buffer: Vec<u8>,
+    scanned: usize,
+    head: Option<(RequestHead, usize)>,
+}
+
+impl RequestParser {
+    pub fn new(limits: RequestLimits) -> RequestParser {
+        RequestParser {
+            limits,
+            buffer: Vec::new(),
+            scanned: 0,
+            head: None,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// true when no byte of a following request has been received
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn stage(&self) -> ReadStage {
+        match (&self.head, self.buffer.is_empty()) {
+            (Some(_), _) => ReadStage::Body,
+            (None, true) => ReadStage::Idle,
+            (None, false) => ReadStage::Head,
+        }
+    }
+
+    /// Try to parse one request out of the buffered bytes.
+    /// Return `Ok(None)` when more bytes are needed.
+    pub fn parse(&mut self) -> Result<Option<PotatoRequest>, RequestError> {
+        if self.head.is_none() {
+            let head_len = match self.find_head_end() {
+                Some(len) => len,
+                None if self.buffer.len() > self.limits.max_header_size => {
+                    return Err(RequestError::HeaderTooLarge)
+                }
+                None => return Ok(None),
+            };
+            let head = parse_head(&self.buffer[..head_len], &self.limits)?;
+            self.head = Some((head, head_len));
+        }
+
+        let (head, head_len) = self.head.as_mut().unwrap(); // safe unwrap
+        let body_end = match &mut head.framing {
+            BodyFraming::Length(len) => *head_len + *len,
+            BodyFraming::Chunked(chunked) => {
+                if chunked.pos < *head_len {
+                    chunked.pos = *head_len;
+                }
+                decode_chunks(chunked, &self.buffer, &self.limits)?;
+                chunked.pos
+            }
+        };
+        let complete = match &head.framing {
+            BodyFraming::Length(_) => self.buffer.len() >= body_end,
+            BodyFraming::Chunked(chunked) => matches!(chunked.state, ChunkState::Done),
+        };
+        if !complete {
+            return Ok(None);
+        }
+
+        let (head, head_len) = self.head.take().unwrap(); // safe unwrap
+        let (body, trailers) = match head.framing {
+            BodyFraming::Length(_) => (self.buffer[head_len..body_end].to_vec(), HeaderMap::new()),
+            BodyFraming::Chunked(chunked) => (chunked.decoded, chunked.trailers),
+        };
+        self.buffer.drain(..body_end);
+        self.scanned = 0;
+
+        let mut req = PotatoRequest::new(head.method, &head.path, Some(body));
+        req.target = head.target;
+        req.query = head.query;
+        req.version = head.version;
+        req.headers = head.headers;
+        req.trailers = trailers;
+        Ok(Some(req))
+    }
+
+    /// Search for the blank line terminating the header section and
+    /// return the length of the head including the terminator
+    fn find_head_end(&mut self) -> Option<usize> {
+        let start = self.scanned.saturating_sub(3);
+        let found = self.buffer[start..]
             .windows(4)
-            .enumerate()
-            .find(|(_, w)| matches!(*w, b"\r\n\r\n"))
-            .map(|(i, _)| i)
-            .unwrap();
+            .position(|w| w == b"\r\n\r\n")
+            .map(|i| start + i + 4);
+        if found.is_none() {
+            self.scanned = self.buffer.len();
+        }
+        found
+    }
+}
+
+fn parse_head(raw: &[u8], limits: &RequestLimits) -> Result<RequestHead, RequestError> {
+    if raw.len() > limits.max_header_size {
+        return Err(RequestError::HeaderTooLarge);
+    }
+    let head = std::str::from_utf8(raw).map_err(|_| RequestError::BadRequest("non UTF-8 head"))?;
+
+    // RFC 7230 section 3.5: ignore empty lines received prior to the request-line
+    let mut lines = head.split("\r\n").skip_while(|line| line.is_empty());
+
+    let request_line = lines.next().unwrap_or("");
+    let mut parts = request_line.split(' ');
+    let (raw_method, target, version) =
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(m), Some(p), Some(v), None) if !p.is_empty() => (m, p, v),
+            _ => return Err(RequestError::BadRequest("malformed request line")),
+        };
+    let method = HttpRequestMethod::from_str(raw_method)
+        .ok_or(RequestError::BadRequest("unknown method"))?;
+    if !version.starts_with("HTTP/1.") {
+        return Err(RequestError::BadRequest("unsupported HTTP version"));
+    }
+
+    let (raw_path, raw_query) = split_target(target);
+    let path = uri::decode_path(raw_path).ok_or(RequestError::BadRequest("invalid path"))?;
+    let query = uri::parse_query(raw_query).ok_or(RequestError::BadRequest("invalid query"))?;
+
+    let mut headers = HeaderMap::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = parse_header_line(line)?;
+        headers.append(name, value);
+    }
+
+    let content_len = content_length(&headers)?;
+    let encodings: Vec<&str> = headers.get_all("Transfer-Encoding").collect();
+    let chunked = is_chunked(&encodings.join(","))?;
+
+    // RFC 7230 section 3.3.3: both framings at once smell of request smuggling
+    let framing = if chunked && headers.contains("Content-Length") {
+        return Err(RequestError::BadRequest(
+            "Content-Length with Transfer-Encoding",
+        ));
+    } else if chunked {
+        BodyFraming::Chunked(ChunkedBody {
+            state: ChunkState::Size,
+            pos: 0,
+            decoded: Vec::new(),
+            trailers: HeaderMap::new(),
+            trailer_size: 0,
+        })
+    } else if content_len > limits.max_body_size {
+        return Err(RequestError::BodyTooLarge);
+    } else {
+        BodyFraming::Length(content_len)
+    };
 
-        let headers = String::from_utf8_lossy(&raw[index..bindex]);
-        let mut content_len = 0;
-        for i in headers.lines() {
-            if i.is_empty() {
-                continue;
+    Ok(RequestHead {
+        method,
+        target: target.to_string(),
+        path,
+        query,
+        version: version.to_string(),
+        headers,
+        framing,
+    })
+}
+
+/// Split a request target into its path and query string.
+/// Absolute-form targets (`http://host/path`) are reduced to their path.
+fn split_target(target: &str) -> (&str, &str) {
+    let target = match target.find("://") {
+        Some(i) if !target.starts_with('/') => {
+            let after_scheme = &target[i + 3..];
+            match after_scheme.find(['/', '?']) {
+                Some(j) => &after_scheme[j..],
+                None => "/",
+            }
+        }
+        _ => target,
+    };
+    let target = target.split('#').next().unwrap_or("");
+    match target.find('?') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (target, ""),
+    }
+}
+
+/// Split a `name: value` line, rejecting anything RFC 7230 section 3.2
+/// does not allow, including whitespace before the colon and obsolete
+/// line folding
+fn parse_header_line(line: &str) -> Result<(&str, &str), RequestError> {
+    let colon = line
+        .find(':')
+        .ok_or(RequestError::BadRequest("malformed header line"))?;
+    let (name, value) = line.split_at(colon);
+    let value = value[1..].trim_matches(|c| c == ' ' || c == '\t');
+    if !header::is_valid_name(name) {
+        return Err(RequestError::BadRequest("invalid header name"));
+    }
+    if !header::is_valid_value(value) {
+        return Err(RequestError::BadRequest("invalid header value"));
+    }
+    Ok((name, value))
+}
+
+/// Value of `Content-Length`, 0 when absent.
+/// RFC 7230 section 3.3.2: repeated values are fine only when identical
+fn content_length(headers: &HeaderMap) -> Result<usize, RequestError> {
+    let mut lengths = headers
+        .get_all("Content-Length")
+        .flat_map(|value| value.split(','))
+        .map(|len| len.trim().parse::<usize>());
+    let content_len = match lengths.next() {
+        Some(first) => first.map_err(|_| RequestError::BadRequest("invalid Content-Length"))?,
+        None => return Ok(0),
+    };
+    if lengths.any(|len| len != Ok(content_len)) {
+        return Err(RequestError::BadRequest("conflicting Content-Length"));
+    }
+    Ok(content_len)
+}
+
+/// Check a `Transfer-Encoding` value, only a lone `chunked` coding is supported
+fn is_chunked(value: &str) -> Result<bool, RequestError> {
+    let codings: Vec<&str> = value
+        .split(',')
+        .map(|coding| coding.trim())
+        .filter(|coding| !coding.is_empty())
+        .collect();
+    match codings.last() {
+        Some(last) if !last.eq_ignore_ascii_case("chunked") => Err(RequestError::BadRequest(
+            "chunked must be the final transfer coding",
+        )),
+        Some(_) if codings.len() > 1 => Err(RequestError::UnsupportedTransferCoding),
+        Some(_) => Ok(true),
+        None => Ok(false),
+    }
+}
+
+/// Decode as many chunks as are available in `buffer`, starting at `chunked.pos`
+fn decode_chunks(
+    chunked: &mut ChunkedBody,
+    buffer: &[u8],
+    limits: &RequestLimits,
+) -> Result<(), RequestError> {
+    loop {
+        match chunked.state {
+            ChunkState::Size => {
+                let too_long = RequestError::BadRequest("chunk size line too long");
+                let line = match find_line(buffer, chunked.pos, MAX_CHUNK_LINE, too_long)? {
+                    Some(line) => line,
+                    None => return Ok(()),
+                };
+                // chunk extensions after ';' carry nothing we act on
+                let size = line.split(';').next().unwrap_or("").trim();
+                // from_str_radix would take a sign too
+                if !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err(RequestError::BadRequest("invalid chunk size"));
+                }
+                let size = usize::from_str_radix(size, 16)
+                    .map_err(|_| RequestError::BadRequest("invalid chunk size"))?;
+                chunked.pos += line.len() + 2;
+
+                // a size near usize::MAX must not wrap around the limit
+                let total = chunked.decoded.len().checked_add(size);
+                if size == 0 {
+                    chunked.state = ChunkState::Trailer;
+                } else if total.is_none_or(|total| total > limits.max_body_size) {
+                    return Err(RequestError::BodyTooLarge);
+                } else {
+                    chunked.state = ChunkState::Data(size);
+                }
+            }
+            ChunkState::Data(size) => {
+                let end = match chunked.pos.checked_add(size) {
+                    Some(end) if end <= usize::MAX - 2 => end,
+                    _ => return Err(RequestError::BodyTooLarge),
+                };
+                if buffer.len() < end + 2 {
+                    return Ok(());
+                }
+                if &buffer[end..end + 2] != b"\r\n" {
+                    return Err(RequestError::BadRequest("missing CRLF after chunk data"));
+                }
+                chunked.decoded.extend_from_slice(&buffer[chunked.pos..end]);
+                chunked.pos = end + 2;
+                chunked.state = ChunkState::Size;
+            }
+            ChunkState::Trailer => {
+                // trailers share the header size budget
+                let remaining = limits.max_header_size.saturating_sub(chunked.trailer_size);
+                let too_long = RequestError::HeaderTooLarge;
+                let line = match find_line(buffer, chunked.pos, remaining, too_long)? {
+                    Some(line) => line,
+                    None => return Ok(()),
+                };
+                chunked.pos += line.len() + 2;
+                chunked.trailer_size += line.len() + 2;
+                if line.is_empty() {
+                    chunked.state = ChunkState::Done;
+                } else {
+                    let (name, value) = parse_header_line(line)?;
+                    chunked.trailers.append(name, value);
+                }
+            }
+            ChunkState::Done => return Ok(()),
+        }
+    }
+}
+
+/// Find a CRLF terminated line starting at `start` and return it without the CRLF.
+/// Fail with `too_long` when no line ending shows up within `max_len` bytes.
+fn find_line(
+    buffer: &[u8],
+    start: usize,
+    max_len: usize,
+    too_long: RequestError,
+) -> Result<Option<&str>, RequestError> {
+    let pending = &buffer[start..];
+    match pending.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end <= max_len => std::str::from_utf8(&pending[..end])
+            .map(Some)
+            .map_err(|_| RequestError::BadRequest("non UTF-8 line")),
+        Some(_) => Err(too_long),
+        None if pending.len() > max_len => Err(too_long),
+        None => Ok(None),
+    }
+}
+
+/// Read requests off a blocking stream with a `RequestParser`
+pub struct RequestReader<R> {
+    inner: R,
+    parser: RequestParser,
+}
+
+impl<R: Read> RequestReader<R> {
+    pub fn new(inner: R, limits: RequestLimits) -> RequestReader<R> {
+        RequestReader {
+            inner,
+            parser: RequestParser::new(limits),
+        }
+    }
+
+    /// Keep reading from the stream until a whole request,
+    /// including its body, has been received
+    pub fn read_request(&mut self) -> Result<PotatoRequest, RequestError> {
+        let mut buffer = [0; 4096];
+        loop {
+            if let Some(req) = self.parser.parse()? {
+                return Ok(req);
             }
-            let (a, b) = i.split_at(i.find(":").unwrap());
-            if a.trim().starts_with("Content-Length") {
-                content_len = b.replace(": ", "").to_string().parse::<usize>().unwrap();
+
+            let n = self.inner.read(&mut buffer)?;
+            if n == 0 {
+                if self.parser.is_empty() {
+                    return Err(RequestError::ConnectionClosed);
+                }
+                return Err(RequestError::BadRequest("connection closed mid-request"));
             }
+            self.parser.feed(&buffer[..n]);
         }
-        let body = raw[bindex + 4..content_len + bindex + 4].to_vec();
+    }
+
+    /// true when bytes of a following request were received along with
+    /// the last one, e.g. pipelined requests
+    pub fn has_pipelined(&self) -> bool {
+        !self.parser.is_empty()
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl RequestReader<Stream> {
+    /// Like `read_request`, giving up once one of `timeouts` expires
+    pub fn read_request_timed(
+        &mut self,
+        timeouts: ReadTimeouts,
+    ) -> Result<PotatoRequest, RequestError> {
+        let mut deadline = ReadDeadline::new(timeouts);
+        let mut buffer = [0; 4096];
+        loop {
+            if let Some(req) = self.parser.parse()? {
+                return Ok(req);
+            }
 
-        PotatoRequest::new(method, path, Some(body))
+            let timeout = deadline.remaining(self.parser.stage())?;
+            self.inner.set_read_timeout(Some(timeout))?;
+            let n = match self.inner.read(&mut buffer) {
+                Ok(n) => n,
+                // the deadline tells whether it is really over
+                Err(e) if is_timeout(&e) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if n == 0 {
+                if self.parser.is_empty() {
+                    return Err(RequestError::ConnectionClosed);
+                }
+                return Err(RequestError::BadRequest("connection closed mid-request"));
+            }
+            self.parser.feed(&buffer[..n]);
+        }
+    }
+}
+
+/// Whether a read failed because its socket timeout expired
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out at most `step` bytes per read, like a slow client
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.step.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    fn parse_all(raw: &[u8]) -> Result<Option<PotatoRequest>, RequestError> {
+        let mut parser = RequestParser::new(RequestLimits::default());
+        parser.feed(raw);
+        parser.parse()
+    }
+
+    fn status_of(raw: &[u8]) -> Option<StatusCode> {
+        match parse_all(raw) {
+            Err(e) => e.status(),
+            Ok(_) => panic!("request accepted"),
+        }
+    }
+
+    #[test]
+    fn split_reads() {
+        let raw = b"POST /echo?x=1 HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello";
+        for step in 1..raw.len() {
+            let mut reader = RequestReader::new(Trickle { data: raw, step }, Default::default());
+            let req = reader.read_request().unwrap();
+            assert!(req.method == HttpRequestMethod::POST);
+            assert_eq!(req.path, "/echo");
+            assert_eq!(req.query_param("x"), Some("1"));
+            assert_eq!(req.body.as_deref(), Some(&b"hello"[..]));
+            assert!(!reader.has_pipelined());
+        }
+    }
+
+    #[test]
+    fn pipelined_reads() {
+        let raw = b"GET /a HTTP/1.1\r\n\r\nPOST /b HTTP/1.1\r\nContent-Length: 2\r\n\r\nokGET /c HTTP/1.1\r\n";
+        let mut reader = RequestReader::new(&raw[..], Default::default());
+        assert_eq!(reader.read_request().unwrap().path, "/a");
+        assert!(reader.has_pipelined());
+        let req = reader.read_request().unwrap();
+        assert_eq!(req.path, "/b");
+        assert_eq!(req.body.as_deref(), Some(&b"ok"[..]));
+        assert!(reader.has_pipelined());
+        // the third one is cut short
+        assert!(matches!(
+            reader.read_request(),
+            Err(RequestError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn closed_before_request() {
+        let mut reader = RequestReader::new(&b""[..], Default::default());
+        assert!(matches!(
+            reader.read_request(),
+            Err(RequestError::ConnectionClosed)
+        ));
+    }
+
+    #[test]
+    fn chunked_body_and_trailers() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;ext=1\r\nhello\r\nA\r\n, chunked!\r\n0\r\nDigest: abc\r\n\r\n";
+        for step in 1..raw.len() {
+            let mut reader = RequestReader::new(Trickle { data: raw, step }, Default::default());
+            let req = reader.read_request().unwrap();
+            assert_eq!(req.body.as_deref(), Some(&b"hello, chunked!"[..]));
+            assert_eq!(req.trailers.get("digest"), Some("abc"));
+        }
+    }
+
+    #[test]
+    fn invalid_chunk_sizes() {
+        for size in ["+5", "-5", "0x5", "5 5", "", "g"].iter() {
+            let raw = format!(
+                "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{}\r\nhello\r\n0\r\n\r\n",
+                size
+            );
+            assert_eq!(status_of(raw.as_bytes()), Some(StatusCode::BAD_REQUEST));
+        }
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffffff\r\n";
+        assert_eq!(status_of(raw), Some(StatusCode::BAD_REQUEST));
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nfffffffffffffff\r\n";
+        assert_eq!(status_of(raw), Some(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    #[test]
+    fn chunked_with_content_length() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n0\r\n\r\n";
+        assert_eq!(status_of(raw), Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn unsupported_transfer_coding() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n";
+        assert_eq!(status_of(raw), Some(StatusCode::NOT_IMPLEMENTED));
+        // the body length is unknown without chunked last
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n";
+        assert_eq!(status_of(raw), Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn oversize_headers() {
+        let limits = RequestLimits {
+            max_header_size: 64,
+            max_body_size: 16,
+        };
+        let mut parser = RequestParser::new(limits);
+        parser.feed(b"GET / HTTP/1.1\r\n");
+        assert!(matches!(parser.parse(), Ok(None)));
+        parser.feed(&[b'a'; 64]);
+        assert!(matches!(parser.parse(), Err(RequestError::HeaderTooLarge)));
+
+        let mut parser = RequestParser::new(limits);
+        parser.feed(b"POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n");
+        assert!(matches!(parser.parse(), Err(RequestError::BodyTooLarge)));
+
+        // trailers count against the header limit
+        let mut parser = RequestParser::new(limits);
+        parser.feed(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n");
+        parser.feed(&[b'a'; 65]);
+        assert!(matches!(parser.parse(), Err(RequestError::HeaderTooLarge)));
+    }
+
+    #[test]
+    fn malformed_heads() {
+        for raw in [
+            &b"GET /\r\n\r\n"[..],
+            b"FETCH / HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/2\r\n\r\n",
+            b"GET / HTTP/1.1\r\nno colon\r\n\r\n",
+            b"GET / HTTP/1.1\r\nContent-Length: 1, 2\r\n\r\n",
+        ]
+        .iter()
+        {
+            assert_eq!(status_of(raw), Some(StatusCode::BAD_REQUEST));
+        }
+    }
+
+    #[test]
+    fn keep_alive() {
+        let req = parse_all(b"GET / HTTP/1.1\r\n\r\n").unwrap().unwrap();
+        assert!(req.keep_alive());
+        let req = parse_all(b"GET / HTTP/1.1\r\nConnection: Close\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert!(!req.keep_alive());
+        let req = parse_all(b"GET / HTTP/1.0\r\n\r\n").unwrap().unwrap();
+        assert!(!req.keep_alive());
+        let req = parse_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert!(req.keep_alive());
     }
 }
@@ -1,46 +1,204 @@
-use std::collections::HashMap;
+use crate::header::{self, HeaderMap, InvalidHeader};
+use crate::request::HttpRequestMethod;
+use crate::status::StatusCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SERVER_NAME: &str = "potato";
 
 pub struct PotatoResponse {
-    status: String,
-    headers: HashMap<String, String>,
+    status: StatusCode,
+    headers: HeaderMap,
     body: Option<Vec<u8>>,
 }
 
+impl Default for PotatoResponse {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PotatoResponse {
+    /// Create an empty `200 OK` response
     pub fn new() -> PotatoResponse {
         PotatoResponse {
-            status: String::new(),
-            headers: HashMap::new(),
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
             body: None,
         }
     }
 
-    pub fn set_status(mut self, status: &str) -> PotatoResponse {
-        self.status = status.to_string();
+    pub fn set_status(mut self, status: StatusCode) -> PotatoResponse {
+        self.status = status;
         self
     }
 
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Add a header, keeping values already set under the same name
+    /// so that e.g. several `Set-Cookie` headers can be sent.
+    ///
+    /// Panics when `key` is not a valid header name or `value` contains
+    /// CR, LF or other control characters. Use `try_add_header` for
+    /// values that come from the request.
     pub fn add_header(mut self, key: &str, value: &str) -> PotatoResponse {
-        self.headers.insert(key.to_string(), value.to_string());
+        if let Err(e) = self.try_add_header(key, value) {
+            panic!("{}", e);
+        }
         self
     }
 
+    /// Replace every value of header `key` with `value`.
+    /// Panics on invalid header like `add_header`
+    pub fn set_header(mut self, key: &str, value: &str) -> PotatoResponse {
+        self.headers.remove(key);
+        self.add_header(key, value)
+    }
+
+    /// Add a header unless it would allow response splitting
+    pub fn try_add_header(&mut self, key: &str, value: &str) -> Result<(), InvalidHeader> {
+        header::validate(key, value)?;
+        self.headers.append(key, value);
+        Ok(())
+    }
+
+    /// First value of header `key`, names are compared case-insensitively
+    pub fn get_header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key)
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Whether the handler asked for the connection to be closed
+    pub fn closes_connection(&self) -> bool {
+        self.headers
+            .get_all("Connection")
+            .flat_map(|value| value.split(','))
+            .any(|opt| opt.trim().eq_ignore_ascii_case("close"))
+    }
+
     pub fn add_body(mut self, content: Vec<u8>) -> PotatoResponse {
         self.body = Some(content);
         self
     }
 
-    pub fn to_http_response(&self) -> Vec<u8> {
-        let mut headers = String::new();
-        for (k, v) in &self.headers {
-            headers = format!("{}: {}\n", k, v);
+    /// Serialize the response as an answer to a `method` request.
+    ///
+    /// `Content-Length` is always computed from the body, `Date` and `Server`
+    /// are filled in unless the handler set them. The body is left out for
+    /// `HEAD` requests and for statuses that must not carry one.
+    pub fn to_http_response(&self, method: HttpRequestMethod) -> Vec<u8> {
+        let body = self.body();
+        let mut headers = self.headers_to_send();
+        if self.status.allows_body() {
+            headers.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+
+        let response = format!("HTTP/1.1 {}\r\n{}\r\n", self.status, headers);
+        if !self.status.allows_body() || method == HttpRequestMethod::HEAD {
+            return response.into_bytes();
         }
 
-        let response = format!("HTTP/1.1 {}\r\n{}\r\n\r\n", self.status, headers.trim_end());
-        if let Some(body) = &self.body {
-            return [response.as_bytes(), &body].concat();
+        [response.as_bytes(), body].concat()
+    }
+
+    /// Serialize the response with a chunked body announcing `trailers`.
+    /// The last chunk is left out for the trailers to be sent with
+    /// `last_chunk` once known. Only for responses with a body to
+    /// requests other than `HEAD`, over HTTP/1.1.
+    pub(crate) fn to_chunked_http_response(&self, trailers: &[&str]) -> Vec<u8> {
+        let body = self.body();
+        let mut headers = self.headers_to_send();
+        headers.push_str("Transfer-Encoding: chunked\r\n");
+        headers.push_str(&format!("Trailer: {}\r\n", trailers.join(", ")));
+
+        let mut response = format!("HTTP/1.1 {}\r\n{}\r\n", self.status, headers).into_bytes();
+        if !body.is_empty() {
+            response.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+            response.extend_from_slice(body);
+            response.extend_from_slice(b"\r\n");
+        }
+        response
+    }
+
+    fn body(&self) -> &[u8] {
+        match &self.body {
+            Some(body) => body,
+            None => &[],
+        }
+    }
+
+    /// Headers of the response with `Date` and `Server` filled in,
+    /// leaving the framing of the body to the caller
+    fn headers_to_send(&self) -> String {
+        let mut headers = String::new();
+        for (k, v) in self.headers.iter() {
+            if k.eq_ignore_ascii_case("Content-Length")
+                || k.eq_ignore_ascii_case("Transfer-Encoding")
+            {
+                continue;
+            }
+            headers.push_str(&format!("{}: {}\r\n", k, v));
         }
+        if !self.headers.contains("Date") {
+            headers.push_str(&format!("Date: {}\r\n", http_date(SystemTime::now())));
+        }
+        if !self.headers.contains("Server") {
+            headers.push_str(&format!("Server: {}\r\n", SERVER_NAME));
+        }
+        headers
+    }
+}
 
-        [response.as_bytes()].concat()
+/// Last chunk ending a body sent by `to_chunked_http_response`,
+/// followed by the `trailers`
+pub(crate) fn last_chunk(trailers: &[(&str, String)]) -> Vec<u8> {
+    let mut chunk = String::from("0\r\n");
+    for (name, value) in trailers {
+        chunk.push_str(&format!("{}: {}\r\n", name, value));
     }
+    chunk.push_str("\r\n");
+    chunk.into_bytes()
+}
+
+/// Format `time` as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    let secs_of_day = secs % 86400;
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Convert days since the unix epoch to a (year, month, day) date.
+/// See Howard Hinnant's `civil_from_days` algorithm.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
 }
@@ -0,0 +1,404 @@
+use crate::request::HttpRequestMethod;
+use std::collections::HashMap;
+
+#[derive(Eq, PartialEq, Hash, Clone)]
+pub struct PotatoRoute {
+    pub method: HttpRequestMethod,
+    pub path: String,
+}
+
+impl PotatoRoute {
+    pub fn new(method: HttpRequestMethod, path: &str) -> PotatoRoute {
+        PotatoRoute {
+            method,
+            path: path.to_string(),
+        }
+    }
+}
+
+/// Outcome of looking up a request in the `Router`
+pub enum RouteMatch<'a, T> {
+    /// route found along with the captured path parameters
+    Found(&'a T, HashMap<String, String>),
+    /// path is known but not for this method, carries the methods it accepts
+    MethodNotAllowed(Vec<HttpRequestMethod>),
+    NotFound,
+}
+
+/// Tree of routes keyed on path segments.
+///
+/// A segment is matched literally unless it starts with `:`, which captures
+/// one segment under the given name, or with `*`, which captures the rest of
+/// the path and must be the last segment of a route.
+/// On conflict literal segments win over `:param`, which wins over `*rest`.
+#[derive(Clone)]
+pub struct Router<T> {
+    root: Node<T>,
+}
+
+/// Values of the routes of a path, by method
+type Methods<T> = HashMap<HttpRequestMethod, T>;
+
+#[derive(Clone)]
+struct Node<T> {
+    statics: HashMap<String, Node<T>>,
+    param: Option<(String, Box<Node<T>>)>,
+    wildcard: Option<(String, HashMap<HttpRequestMethod, T>)>,
+    values: HashMap<HttpRequestMethod, T>,
+}
+
+impl<T> Node<T> {
+    fn new() -> Node<T> {
+        Node {
+            statics: HashMap::new(),
+            param: None,
+            wildcard: None,
+            values: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Default for Router<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Router<T> {
+    pub fn new() -> Router<T> {
+        Router { root: Node::new() }
+    }
+
+    /// Add a route, replacing the value of an identical route if any.
+    ///
+    /// Fails, leaving the router as it was, when the route clashes with
+    /// another one on the name of a parameter or when a wildcard is not
+    /// the last segment.
+    pub fn insert(&mut self, route: &PotatoRoute, value: T) -> Result<(), String> {
+        self.check(route)?;
+        let segments: Vec<&str> = split_path(&route.path).collect();
+        let mut node = &mut self.root;
+
+        for segment in &segments {
+            if let Some(name) = segment.strip_prefix('*') {
+                let (_, values) = node
+                    .wildcard
+                    .get_or_insert_with(|| (name.to_string(), HashMap::new()));
+                values.insert(route.method, value);
+                return Ok(());
+            }
+
+            node = match segment.strip_prefix(':') {
+                Some(name) => {
+                    let (_, child) = node
+                        .param
+                        .get_or_insert_with(|| (name.to_string(), Box::new(Node::new())));
+                    child
+                }
+                None => node
+                    .statics
+                    .entry(segment.to_string())
+                    .or_insert_with(Node::new),
+            };
+        }
+        node.values.insert(route.method, value);
+        Ok(())
+    }
+
+    /// Tell why `route` can't be inserted, if it can't
+    fn check(&self, route: &PotatoRoute) -> Result<(), String> {
+        let segments: Vec<&str> = split_path(&route.path).collect();
+        let mut node = Some(&self.root);
+
+        for (i, segment) in segments.iter().enumerate() {
+            if let Some(name) = segment.strip_prefix('*') {
+                if i != segments.len() - 1 {
+                    return Err(format!(
+                        "[{}] wildcard must be the last segment",
+                        route.path
+                    ));
+                }
+                if let Some((wc_name, _)) = node.and_then(|node| node.wildcard.as_ref()) {
+                    if wc_name != name {
+                        return Err(format!(
+                            "[{}] conflicts with wildcard *{}",
+                            route.path, wc_name
+                        ));
+                    }
+                }
+                continue;
+            }
+
+            node = match (segment.strip_prefix(':'), node) {
+                (Some(name), Some(parent)) => match &parent.param {
+                    Some((param_name, _)) if param_name != name => {
+                        return Err(format!(
+                            "[{}] conflicts with parameter :{}",
+                            route.path, param_name
+                        ));
+                    }
+                    Some((_, child)) => Some(child),
+                    None => None,
+                },
+                (None, Some(parent)) => parent.statics.get(*segment),
+                // the rest of the route is new, nothing to clash with
+                (_, None) => None,
+            };
+        }
+        Ok(())
+    }
+
+    /// Find the route for `method` and `path`, `path` must not contain a query string.
+    /// `HEAD` falls back to the `GET` route of the path unless it has one of its own.
+    ///
+    /// Less specific branches are tried when a branch lacks `method`, the
+    /// path is only answered with `MethodNotAllowed` when no branch has it.
+    pub fn lookup(&self, method: HttpRequestMethod, path: &str) -> RouteMatch<'_, T> {
+        let segments: Vec<&str> = split_path(path).collect();
+        let mut found = None;
+        let mut allowed = Vec::new();
+        walk(
+            &self.root,
+            &segments,
+            &mut Vec::new(),
+            &mut |values, params| {
+                let value = values.get(&method).or_else(|| match method {
+                    HttpRequestMethod::HEAD => values.get(&HttpRequestMethod::GET),
+                    _ => None,
+                });
+                if let Some(value) = value {
+                    found = Some((value, params.iter().cloned().collect()));
+                    return true;
+                }
+                allowed.extend(values.keys().copied());
+                if values.contains_key(&HttpRequestMethod::GET) {
+                    allowed.push(HttpRequestMethod::HEAD);
+                }
+                false
+            },
+        );
+
+        match found {
+            Some((value, params)) => RouteMatch::Found(value, params),
+            None if allowed.is_empty() => RouteMatch::NotFound,
+            None => {
+                allowed.sort();
+                allowed.dedup();
+                RouteMatch::MethodNotAllowed(allowed)
+            }
+        }
+    }
+
+    /// Values of every route, in no particular order
+    pub fn values(&self) -> Vec<&T> {
+        let mut values = Vec::new();
+        collect_values(&self.root, &mut values);
+        values
+    }
+}
+
+fn collect_values<'a, T>(node: &'a Node<T>, values: &mut Vec<&'a T>) {
+    values.extend(node.values.values());
+    if let Some((_, wildcard)) = &node.wildcard {
+        values.extend(wildcard.values());
+    }
+    if let Some((_, child)) = &node.param {
+        collect_values(child, values);
+    }
+    for child in node.statics.values() {
+        collect_values(child, values);
+    }
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+/// Walk down every branch of the tree matching `segments`, most specific
+/// first, handing the routes of each to `visit` until it returns true
+fn walk<'a, T, F>(
+    node: &'a Node<T>,
+    segments: &[&str],
+    params: &mut Vec<(String, String)>,
+    visit: &mut F,
+) -> bool
+where
+    F: FnMut(&'a Methods<T>, &[(String, String)]) -> bool,
+{
+    match segments.split_first() {
+        None => {
+            if !node.values.is_empty() && visit(&node.values, params) {
+                return true;
+            }
+        }
+        Some((segment, rest)) => {
+            if let Some(child) = node.statics.get(*segment) {
+                if walk(child, rest, params, visit) {
+                    return true;
+                }
+            }
+            if let Some((name, child)) = &node.param {
+                params.push((name.to_string(), segment.to_string()));
+                if walk(child, rest, params, visit) {
+                    return true;
+                }
+                params.pop();
+            }
+        }
+    }
+
+    // a wildcard also matches an empty remainder
+    if let Some((name, values)) = &node.wildcard {
+        params.push((name.to_string(), segments.join("/")));
+        if visit(values, params) {
+            return true;
+        }
+        params.pop();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::HttpRequestMethod::*;
+
+    fn router_of(routes: &[(HttpRequestMethod, &str)]) -> Router<String> {
+        let mut router = Router::new();
+        for (method, path) in routes {
+            let value = format!("{} {}", method, path);
+            router
+                .insert(&PotatoRoute::new(*method, path), value)
+                .unwrap();
+        }
+        router
+    }
+
+    fn found(
+        router: &Router<String>,
+        method: HttpRequestMethod,
+        path: &str,
+    ) -> (String, Vec<(String, String)>) {
+        match router.lookup(method, path) {
+            RouteMatch::Found(value, params) => {
+                let mut params: Vec<(String, String)> = params.into_iter().collect();
+                params.sort();
+                (value.clone(), params)
+            }
+            _ => panic!("[{} {}] no route found", method, path),
+        }
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn params_and_wildcards() {
+        let router = router_of(&[
+            (GET, "/"),
+            (GET, "/users/:id"),
+            (GET, "/users/:id/posts/:post"),
+            (GET, "/users/me"),
+            (GET, "/files/*path"),
+        ]);
+        assert_eq!(found(&router, GET, "/"), ("GET /".to_string(), vec![]));
+        assert_eq!(found(&router, GET, "/users/me").0, "GET /users/me");
+        assert_eq!(
+            found(&router, GET, "/users/42/posts/7"),
+            (
+                "GET /users/:id/posts/:post".to_string(),
+                params(&[("id", "42"), ("post", "7")])
+            )
+        );
+        assert_eq!(
+            found(&router, GET, "/files/a/b.txt"),
+            (
+                "GET /files/*path".to_string(),
+                params(&[("path", "a/b.txt")])
+            )
+        );
+        assert_eq!(found(&router, GET, "/files").1, params(&[("path", "")]));
+        assert!(matches!(router.lookup(GET, "/users"), RouteMatch::NotFound));
+        assert!(matches!(
+            router.lookup(GET, "/users/42/x"),
+            RouteMatch::NotFound
+        ));
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        let router = router_of(&[(GET, "/a"), (GET, "/b"), (HEAD, "/b")]);
+        assert_eq!(found(&router, HEAD, "/a").0, "GET /a");
+        assert_eq!(found(&router, HEAD, "/b").0, "HEAD /b");
+    }
+
+    #[test]
+    fn backtracks_on_method() {
+        let router = router_of(&[(POST, "/files/upload"), (GET, "/files/*rest")]);
+        assert_eq!(
+            found(&router, POST, "/files/upload").0,
+            "POST /files/upload"
+        );
+        assert_eq!(
+            found(&router, GET, "/files/upload"),
+            (
+                "GET /files/*rest".to_string(),
+                params(&[("rest", "upload")])
+            )
+        );
+
+        let router = router_of(&[(POST, "/users/me"), (GET, "/users/:id")]);
+        assert_eq!(found(&router, GET, "/users/me").1, params(&[("id", "me")]));
+    }
+
+    #[test]
+    fn method_not_allowed() {
+        let router = router_of(&[
+            (POST, "/files/upload"),
+            (PUT, "/files/*rest"),
+            (GET, "/other"),
+        ]);
+        match router.lookup(DELETE, "/files/upload") {
+            RouteMatch::MethodNotAllowed(allowed) => {
+                assert!(allowed == vec![POST, PUT]);
+            }
+            _ => panic!("expected 405"),
+        }
+        match router.lookup(POST, "/other") {
+            RouteMatch::MethodNotAllowed(allowed) => assert!(allowed == vec![GET, HEAD]),
+            _ => panic!("expected 405"),
+        }
+    }
+
+    #[test]
+    fn conflicts() {
+        let mut router = router_of(&[(GET, "/users/:id"), (GET, "/files/*path")]);
+        let clashes = [
+            (GET, "/users/:name"),
+            (POST, "/users/:name/posts"),
+            (GET, "/files/*rest"),
+            (GET, "/static/*path/more"),
+        ];
+        for (method, path) in clashes.iter() {
+            assert!(router
+                .insert(&PotatoRoute::new(*method, path), String::new())
+                .is_err());
+        }
+        // nothing of the failed inserts is left behind
+        assert!(matches!(
+            router.lookup(GET, "/static/x/more"),
+            RouteMatch::NotFound
+        ));
+        assert_eq!(router.values().len(), 2);
+
+        router
+            .insert(&PotatoRoute::new(GET, "/users/:id"), "again".to_string())
+            .unwrap();
+        assert_eq!(found(&router, GET, "/users/1").0, "again");
+        assert_eq!(router.values().len(), 2);
+    }
+}
@@ -1,197 +1,1007 @@
-use crate::request::{HttpRequestMethod, PotatoRequest};
+use crate::access_log::{AccessLog, LogEntry, LogFormat};
+use crate::handler::{AsyncHandler, Handler};
+use crate::libc;
+use crate::listener::{self, ListenAddr, Listener};
+use crate::metrics::Metrics;
+use crate::middleware::{Chain, Filter, Middleware};
+use crate::pool::{PoolMetrics, WorkerPool};
+use crate::request::{
+    HttpRequestMethod, PotatoRequest, ReadTimeouts, RequestError, RequestLimits, RequestReader,
+};
 use crate::response::PotatoResponse;
+use crate::router::{RouteMatch, Router};
+use crate::shutdown::{ConnectionGuard, Inflight};
+use crate::state::{AppState, Snapshot};
+use crate::status::StatusCode;
+use crate::stream::Stream;
+use crate::tls::{Terminator, TlsConfig};
 use crate::{
-    isolation::{isolate_req, IsolationSetting},
+    isolation::{isolate_req, IsolationSetting, ReportReader, Sandbox},
     prep,
 };
-use libpotato::{net, signal};
+use libpotato::{cgroup, net, signal, signal_hook as sighook};
+use std::any::Any;
 use std::collections::HashMap;
 use std::fs;
-use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
+use std::io::{self, prelude::*};
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
-pub type PotatoRequestHandler = fn(PotatoRequest) -> PotatoResponse;
+pub type PotatoRequestHandler = Arc<dyn Handler>;
+pub type AsyncRequestHandler = Arc<dyn AsyncHandler>;
+pub type ReloadHandler = Arc<dyn Fn() -> PotatoServer + Send + Sync>;
 
-#[derive(Eq, PartialEq, Hash, Clone)]
-pub struct PotatoRoute {
-    method: HttpRequestMethod,
-    path: String,
+/// What a route dispatches to
+#[derive(Clone)]
+pub(crate) enum Endpoint {
+    Blocking(PotatoRequestHandler),
+    Async(AsyncRequestHandler),
 }
 
+/// Route target along with the sandbox it runs in
+#[derive(Clone)]
+struct Target {
+    /// path the route was registered with, `None` for the default handler
+    path: Option<String>,
+    endpoint: Endpoint,
+    isolation: Option<IsolationSetting>,
+}
+
+pub use crate::router::PotatoRoute;
+
 #[derive(Clone)]
 pub struct PotatoServer {
-    port: String,
-    runtime_dir: String,
-    handlers: Vec<(PotatoRoute, PotatoRequestHandler, Option<IsolationSetting>)>,
-    default_handler: Option<(PotatoRequestHandler, Option<IsolationSetting>)>,
-    isolation: bool,
+    pub(crate) port: String,
+    listen_addrs: Vec<ListenAddr>,
+    pub(crate) runtime_dir: String,
+    router: Router<Target>,
+    default_handler: Option<Target>,
+    has_async_handlers: bool,
+    pub(crate) isolation: bool,
+    middlewares: Vec<Arc<dyn Middleware>>,
+    filters: Vec<Arc<dyn Filter>>,
+    pub(crate) state: AppState,
+    pub(crate) limits: RequestLimits,
+    pub(crate) keep_alive_timeout: Duration,
+    pub(crate) max_keep_alive_requests: usize,
+    header_read_timeout: Duration,
+    body_read_timeout: Duration,
+    pub(crate) write_timeout: Duration,
+    pub(crate) handler_timeout: Option<Duration>,
+    pub(crate) access_log: Option<Arc<AccessLog>>,
+    pub(crate) metrics: Option<Arc<Metrics>>,
+    worker_pool: Option<(usize, usize)>,
+    pool_metrics: Arc<PoolMetrics>,
+    pub(crate) inflight: Arc<Inflight>,
+    pub(crate) shutdown_timeout: Duration,
+    reload_handler: Option<ReloadHandler>,
+    tls: Option<TlsConfig>,
+    /// forked by `start` when serving TLS, kept across reloads
+    terminator: Option<Arc<Terminator>>,
+    bridge_subnet: String,
+    cgroup_root: Option<String>,
+    accounting: bool,
+    server_timing: bool,
 }
 
 impl PotatoServer {
     pub fn new(port: &str, runtime_dir: &str, isolation: bool) -> PotatoServer {
         PotatoServer {
             port: port.to_string(),
+            listen_addrs: Vec::new(),
             runtime_dir: runtime_dir.to_string(),
-            handlers: Vec::new(),
+            router: Router::new(),
             default_handler: None,
+            has_async_handlers: false,
             isolation,
+            middlewares: Vec::new(),
+            filters: Vec::new(),
+            state: AppState::new(),
+            limits: RequestLimits::default(),
+            keep_alive_timeout: Duration::from_secs(5),
+            max_keep_alive_requests: 100,
+            header_read_timeout: Duration::from_secs(10),
+            body_read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            handler_timeout: None,
+            access_log: None,
+            metrics: None,
+            worker_pool: None,
+            pool_metrics: Arc::new(PoolMetrics::new()),
+            inflight: Arc::new(Inflight::new()),
+            shutdown_timeout: Duration::from_secs(30),
+            reload_handler: None,
+            tls: None,
+            terminator: None,
+            bridge_subnet: "10.0.0.0/24".to_string(),
+            cgroup_root: None,
+            accounting: false,
+            server_timing: false,
         }
     }
 
+    /// Set how long an idle persistent connection is kept open
+    /// waiting for the next request
+    pub fn set_keep_alive_timeout(mut self, timeout: Duration) -> PotatoServer {
+        self.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// Set how long a client has to send the request line and headers once
+    /// the first byte of a request arrived, `408` is sent past it
+    pub fn set_header_read_timeout(mut self, timeout: Duration) -> PotatoServer {
+        assert!(!timeout.is_zero(), "header read timeout must not be zero");
+        self.header_read_timeout = timeout;
+        self
+    }
+
+    /// Set how long a client has to send the body once the headers
+    /// arrived, `408` is sent past it
+    pub fn set_body_read_timeout(mut self, timeout: Duration) -> PotatoServer {
+        assert!(!timeout.is_zero(), "body read timeout must not be zero");
+        self.body_read_timeout = timeout;
+        self
+    }
+
+    /// Set how long a single write of the response may block
+    /// on a client that doesn't read
+    pub fn set_write_timeout(mut self, timeout: Duration) -> PotatoServer {
+        assert!(!timeout.is_zero(), "write timeout must not be zero");
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// Answer `504` when a handler takes longer than `timeout`.
+    ///
+    /// Isolated handlers are killed along with their sandbox. Other
+    /// handlers then run on a thread of their own which can't be stopped,
+    /// it keeps running until the handler returns and its response is dropped.
+    pub fn set_handler_timeout(mut self, timeout: Duration) -> PotatoServer {
+        assert!(!timeout.is_zero(), "handler timeout must not be zero");
+        self.handler_timeout = Some(timeout);
+        self
+    }
+
+    /// Record every request served to the file at `path`, which is reopened
+    /// upon SIGUSR2 once rotated. An isolated request then holds on to its
+    /// connection thread until its sandbox exits, to log how it answered.
+    pub fn set_access_log(mut self, path: &str, format: LogFormat) -> PotatoServer {
+        match AccessLog::open(path, format) {
+            Ok(log) => self.access_log = Some(Arc::new(log)),
+            Err(e) => panic!("[{}] failed opening access log: {}", path, e),
+        }
+        self
+    }
+
+    /// Collect request and sandbox metrics, served in the Prometheus text
+    /// format on `GET path`. The endpoint is always served by the server
+    /// process, even with isolation. Like the access log, an isolated
+    /// request holds on to its connection thread until its sandbox exits.
+    pub fn enable_metrics(mut self, path: &str) -> PotatoServer {
+        let metrics = Arc::new(Metrics::new());
+        let pool = Arc::clone(&self.pool_metrics);
+        self.metrics = Some(Arc::clone(&metrics));
+        self.add_handler_with_isolation(
+            HttpRequestMethod::GET,
+            path,
+            move |_req: PotatoRequest| metrics.response(&pool),
+            None,
+        )
+    }
+
+    /// Metrics registry, `None` unless enabled with `enable_metrics`
+    pub fn metrics(&self) -> Option<Arc<Metrics>> {
+        self.metrics.clone()
+    }
+
+    /// Set the maximum number of requests served over one connection.
+    /// Setting it to 1 disables persistent connections.
+    ///
+    /// Only applies to server without isolation, isolated requests are
+    /// always served one per connection.
+    pub fn set_max_keep_alive_requests(mut self, max: usize) -> PotatoServer {
+        assert!(max > 0, "max keep-alive requests must be at least 1");
+        self.max_keep_alive_requests = max;
+        self
+    }
+
+    /// Serve connections on a pool of `workers` threads instead of a new
+    /// thread per connection. Up to `queue_size` accepted connections wait
+    /// for a free worker, further ones are answered with `503` right away.
+    pub fn set_worker_pool(mut self, workers: usize, queue_size: usize) -> PotatoServer {
+        assert!(workers > 0, "worker pool needs at least 1 worker");
+        self.worker_pool = Some((workers, queue_size));
+        self
+    }
+
+    /// Load counters of the worker pool, all zero without one
+    pub fn pool_metrics(&self) -> Arc<PoolMetrics> {
+        Arc::clone(&self.pool_metrics)
+    }
+
+    /// Create the cgroups of sandboxes with resource limits under `path`,
+    /// a cgroup v2 directory delegated to the server, e.g. by systemd with
+    /// `Delegate=yes`. It must not have any process of its own, the server
+    /// enables the controllers the limits need for its children.
+    pub fn set_cgroup_root(mut self, path: &str) -> PotatoServer {
+        assert!(
+            Path::new(path).join("cgroup.procs").is_file(),
+            "[{}] not a cgroup v2 directory",
+            path
+        );
+        self.cgroup_root = Some(path.to_string());
+        self
+    }
+
+    /// Account the resources every sandbox uses, see
+    /// `IsolationSetting::enable_accounting`. Needs a cgroup root.
+    pub fn enable_accounting(mut self) -> PotatoServer {
+        self.accounting = true;
+        self
+    }
+
+    /// Send the resources every sandbox used as a `Server-Timing` trailer,
+    /// see `IsolationSetting::enable_server_timing`. Needs a cgroup root.
+    pub fn enable_server_timing(mut self) -> PotatoServer {
+        self.accounting = true;
+        self.server_timing = true;
+        self
+    }
+
+    /// Listen on `addr`, an IPv4 or IPv6 socket address such as
+    /// `127.0.0.1:8080` or `[::1]:8080`. Can be called several times to
+    /// listen on several addresses. Without any listener the server
+    /// listens on `0.0.0.0` on the port given to `new`.
+    pub fn bind(mut self, addr: &str) -> PotatoServer {
+        let addr: SocketAddr = match addr.parse() {
+            Ok(addr) => addr,
+            Err(_) => panic!("[{}] invalid socket address", addr),
+        };
+        self.listen_addrs.push(ListenAddr::Tcp(addr));
+        self
+    }
+
+    /// Listen on a Unix domain socket created at `path` with permissions
+    /// `mode`, e.g. `0o660` to only let a reverse proxy in the same group
+    /// connect. The socket file is removed when the server stops.
+    /// Connections on Unix sockets are never served over TLS.
+    pub fn bind_unix(mut self, path: &str, mode: u32) -> PotatoServer {
+        self.listen_addrs.push(ListenAddr::Unix {
+            path: PathBuf::from(path),
+            mode,
+        });
+        self
+    }
+
+    /// Listen on the sockets passed by systemd socket activation, if any
+    pub fn bind_systemd(mut self) -> PotatoServer {
+        let fds = listener::systemd_listen_fds();
+        self.listen_addrs
+            .extend(fds.into_iter().map(ListenAddr::Fd));
+        self
+    }
+
+    /// Listen on `fd`, a TCP or Unix domain socket already bound and
+    /// listening, e.g. inherited from a supervisor
+    pub fn bind_fd(mut self, fd: RawFd) -> PotatoServer {
+        self.listen_addrs.push(ListenAddr::Fd(fd));
+        self
+    }
+
+    /// IPv4 subnet of the bridge sandboxes are connected to,
+    /// `10.0.0.0/24` by default
+    pub fn set_bridge_subnet(mut self, subnet: &str) -> PotatoServer {
+        assert!(
+            prep::is_ipv4_cidr(subnet),
+            "[{}] invalid IPv4 subnet",
+            subnet
+        );
+        self.bridge_subnet = subnet.to_string();
+        self
+    }
+
+    /// Serve HTTPS with the certificates of `tls`. TLS is terminated by a
+    /// process `start` forks before starting any thread, which alone loads
+    /// the certificates: the server and isolated handlers only get the
+    /// decrypted stream and never hold the keys nor the TLS session.
+    /// A reloaded server hands its own TLS config to that process, which
+    /// allows rotating certificates on SIGHUP. TLS can't be turned on by
+    /// a reload though.
+    pub fn set_tls(mut self, tls: TlsConfig) -> PotatoServer {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Set how long in-flight requests and sandboxes get to finish on
+    /// SIGTERM or SIGINT before the remaining sandboxes are killed
+    pub fn set_shutdown_timeout(mut self, timeout: Duration) -> PotatoServer {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Rebuild the server with `reload` on SIGHUP. The new routes, handlers
+    /// and settings apply to connections accepted from then on, while the
+    /// port, runtime directory, isolation and worker pool of the running
+    /// server are kept. A panicking `reload`, or one adding async handlers
+    /// to a server run by `start`, leaves the server as it was.
+    pub fn set_reload_handler(
+        mut self,
+        reload: impl Fn() -> PotatoServer + Send + Sync + 'static,
+    ) -> PotatoServer {
+        self.reload_handler = Some(Arc::new(reload));
+        self
+    }
+
+    /// Wrap every handler in `middleware`. It runs alongside the handler:
+    /// in the server process for normal routes and inside the sandbox
+    /// for isolated routes.
+    pub fn add_middleware(mut self, middleware: impl Middleware) -> PotatoServer {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// Run `filter` in the server process on every routed request before
+    /// it is dispatched, isolated or not, e.g. to reject unauthenticated
+    /// requests before paying for a sandbox.
+    pub fn add_filter(mut self, filter: impl Filter) -> PotatoServer {
+        self.filters.push(Arc::new(filter));
+        self
+    }
+
+    /// Share `state` with handlers running in the server process,
+    /// handlers get it back with `PotatoRequest::state::<T>()`.
+    /// Isolated handlers never see it, see `add_snapshot_state`.
+    pub fn add_state<T: Any + Send + Sync>(mut self, state: T) -> PotatoServer {
+        self.state.insert(state);
+        self
+    }
+
+    /// Share `state` with every handler. Isolated handlers get a fresh
+    /// `Snapshot::snapshot` of it taken right before their sandbox is created.
+    pub fn add_snapshot_state<T: Snapshot>(mut self, state: T) -> PotatoServer {
+        self.state.insert_snapshot(state);
+        self
+    }
+
+    /// Set the maximum size of the request line and headers.
+    /// Larger requests are answered with `431 Request Header Fields Too Large`
+    pub fn set_max_header_size(mut self, size: usize) -> PotatoServer {
+        self.limits.max_header_size = size;
+        self
+    }
+
+    /// Set the maximum size of a request body.
+    /// Larger requests are answered with `413 Payload Too Large`
+    pub fn set_max_body_size(mut self, size: usize) -> PotatoServer {
+        self.limits.max_body_size = size;
+        self
+    }
+
     pub fn add_handler(
         self,
         method: HttpRequestMethod,
         path: &str,
-        handler: PotatoRequestHandler,
+        handler: impl Handler,
     ) -> PotatoServer {
         self.add_handler_with_isolation(method, path, handler, None)
     }
 
+    /// Register `handler` for `method` requests on `path`.
+    ///
+    /// Path segments starting with `:` capture a single segment and a last
+    /// segment starting with `*` captures the rest of the path, e.g.
+    /// `/users/:id` or `/static/*file`. Captured values are available
+    /// through `PotatoRequest::params`. Panics when the route clashes with
+    /// another one, see `Router::insert`.
     pub fn add_handler_with_isolation(
         mut self,
         method: HttpRequestMethod,
         path: &str,
-        handler: PotatoRequestHandler,
+        handler: impl Handler,
         opt_isolation: Option<IsolationSetting>,
     ) -> PotatoServer {
-        let route = PotatoRoute {
-            method,
-            path: path.to_string(),
+        let route = PotatoRoute::new(method, path);
+        let target = Target {
+            path: Some(route.path.clone()),
+            endpoint: Endpoint::Blocking(Arc::new(handler)),
+            isolation: opt_isolation,
+        };
+        if let Err(e) = self.router.insert(&route, target) {
+            panic!("{}", e);
+        }
+        self
+    }
+
+    /// Register an async `handler` for `method` requests on `path`,
+    /// see `add_handler_with_isolation` for the path syntax.
+    /// Async handlers are only served by `start_async`.
+    pub fn add_async_handler(
+        mut self,
+        method: HttpRequestMethod,
+        path: &str,
+        handler: impl AsyncHandler,
+    ) -> PotatoServer {
+        let route = PotatoRoute::new(method, path);
+        let target = Target {
+            path: Some(route.path.clone()),
+            endpoint: Endpoint::Async(Arc::new(handler)),
+            isolation: None,
         };
-        self.handlers.push((route, handler, opt_isolation));
+        if let Err(e) = self.router.insert(&route, target) {
+            panic!("{}", e);
+        }
+        self.has_async_handlers = true;
         self
     }
 
-    pub fn add_default_handler(self, handler: PotatoRequestHandler) -> PotatoServer {
+    pub fn add_default_handler(self, handler: impl Handler) -> PotatoServer {
         self.add_default_handler_with_isolation(handler, None)
     }
 
     pub fn add_default_handler_with_isolation(
         mut self,
-        handler: PotatoRequestHandler,
+        handler: impl Handler,
         opt_isolation: Option<IsolationSetting>,
     ) -> PotatoServer {
-        self.default_handler = Some((handler, opt_isolation));
+        self.default_handler = Some(Target {
+            path: None,
+            endpoint: Endpoint::Blocking(Arc::new(handler)),
+            isolation: opt_isolation,
+        });
         self
     }
 
-    pub fn start(self) {
-        let address = format!("0.0.0.0:{}", self.port);
-        let listener = TcpListener::bind(&address).unwrap();
+    pub fn start(mut self) {
+        assert!(
+            !self.has_async_handlers,
+            "async handlers are only served by start_async"
+        );
+        let listeners = self.bind_listeners();
+        self.start_terminator();
+
+        self.prep_host();
+        if self.isolation {
+            signal::ignore_sigchld().expect("Abort: cuz dont want zombie");
+        }
+        print_banner(&listeners);
+
+        let inflight = Arc::clone(&self.inflight);
+        let reaper = Arc::clone(&self.inflight);
+        thread::spawn(move || reaper.reap());
+        let shutdown_timeout = self.shutdown_timeout;
+        let protected_runtime_dir = Arc::new(Mutex::new(self.runtime_dir.clone()));
+        let pool = self.worker_pool.map(|(workers, queue_size)| {
+            let runtime_dir = Arc::clone(&protected_runtime_dir);
+            WorkerPool::new(
+                workers,
+                queue_size,
+                Arc::clone(&self.pool_metrics),
+                move |(server, stream, _guard): Connection| server.serve(stream, &runtime_dir),
+            )
+        });
+        let current = Arc::new(RwLock::new(Arc::new(self)));
+        let listener_fds = listeners.iter().map(|l| l.as_raw_fd()).collect();
+        handle_signals(Arc::clone(&current), listener_fds, false);
+
+        // one accept loop per listener, all of them end on shutdown
+        thread::scope(|scope| {
+            for listener in &listeners {
+                let current = &current;
+                let pool = pool.as_ref();
+                let protected_runtime_dir = &protected_runtime_dir;
+                scope.spawn(move || {
+                    accept_loop(listener, current, pool, protected_runtime_dir);
+                });
+            }
+        });
+
+        println!("Shutting down, waiting for in-flight requests");
+        inflight.drain(Instant::now() + shutdown_timeout);
+    }
+
+    /// Bind every listener, panics when one can't be bound
+    pub(crate) fn bind_listeners(&self) -> Vec<Listener> {
+        let default = [ListenAddr::Tcp(
+            format!("0.0.0.0:{}", self.port)
+                .parse()
+                .expect("Invalid port"),
+        )];
+        let addrs = match self.listen_addrs.is_empty() {
+            true => &default[..],
+            false => &self.listen_addrs[..],
+        };
+        addrs
+            .iter()
+            .map(|addr| match Listener::bind(addr) {
+                Ok(listener) => listener,
+                Err(e) => panic!("[{}] failed to listen: {}", addr, e),
+            })
+            .collect()
+    }
+
+    /// Fork the TLS terminator and load the certificates, panics when they
+    /// can't be loaded. Must run before the server starts any thread.
+    pub(crate) fn start_terminator(&mut self) {
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => return,
+        };
+        let terminator = Terminator::spawn().expect("Failed forking the TLS terminator");
+        if let Err(e) = terminator.load(tls) {
+            panic!("Failed loading TLS certificates: {}", e);
+        }
+        self.terminator = Some(Arc::new(terminator));
+    }
+
+    /// The TLS terminator, when serving TLS
+    pub(crate) fn tls_terminator(&self) -> Option<&Terminator> {
+        self.tls.as_ref().and(self.terminator.as_deref())
+    }
+
+    /// Server built by the reload handler, carrying over what can't change
+    /// without restarting. `Err` when the server started by `start` or
+    /// `start_async`, as told by `serves_async`, can't serve it.
+    fn reloaded(&self, mut next: PotatoServer, serves_async: bool) -> Result<PotatoServer, String> {
+        if next.has_async_handlers && !serves_async {
+            return Err("async handlers are only served by start_async".to_string());
+        }
+        next.port = self.port.clone();
+        next.listen_addrs = self.listen_addrs.clone();
+        next.runtime_dir = self.runtime_dir.clone();
+        next.isolation = self.isolation;
+        next.worker_pool = self.worker_pool;
+        next.pool_metrics = Arc::clone(&self.pool_metrics);
+        next.inflight = Arc::clone(&self.inflight);
+        next.cgroup_root = self.cgroup_root.clone();
+        next.reload_handler = self.reload_handler.clone();
+        next.terminator = self.terminator.clone();
+        if next.isolation {
+            next.prep_cgroups()?;
+        }
+        // last, the terminator serves the new certificates right away
+        if let Some(tls) = &next.tls {
+            match &next.terminator {
+                Some(terminator) => terminator
+                    .load(tls)
+                    .map_err(|e| format!("failed loading TLS certificates: {}", e))?,
+                None => return Err("TLS can only be turned on by a restart".to_string()),
+            }
+        }
+        Ok(next)
+    }
+
+    /// Enable the controllers the resource limits of the routes need,
+    /// and those accounting reads from when the root has them
+    fn prep_cgroups(&self) -> Result<(), String> {
+        let settings: Vec<IsolationSetting> = self
+            .router
+            .values()
+            .into_iter()
+            .chain(&self.default_handler)
+            .filter_map(|target| target.isolation.clone())
+            .map(|setting| self.sandbox_setting(setting))
+            .collect();
+        if !settings.iter().any(IsolationSetting::has_cgroup) {
+            return Ok(());
+        }
+        let root = match &self.cgroup_root {
+            Some(root) => root,
+            None => {
+                return Err(
+                    "resource limits and accounting need a cgroup root, see set_cgroup_root"
+                        .to_string(),
+                )
+            }
+        };
 
+        let mut controllers: Vec<&str> = settings
+            .iter()
+            .flat_map(IsolationSetting::controllers)
+            .collect();
+        if settings.iter().any(|setting| setting.accounting) {
+            // `cpu.stat` is there without the cpu controller
+            let available = cgroup::available_controllers(Path::new(root)).unwrap_or_default();
+            for optional in ["memory", "io"].iter() {
+                if available.iter().any(|c| c == optional) {
+                    controllers.push(optional);
+                }
+            }
+        }
+        if controllers.is_empty() {
+            return Ok(());
+        }
+        controllers.sort_unstable();
+        controllers.dedup();
+        cgroup::enable_controllers(Path::new(root), &controllers)
+            .map_err(|e| format!("[{}] failed enabling cgroup controllers: {}", root, e))
+    }
+
+    /// Fill in what `setting` leaves to the server
+    pub(crate) fn sandbox_setting(&self, mut setting: IsolationSetting) -> IsolationSetting {
+        if setting.timeout.is_none() {
+            setting.timeout = self.handler_timeout;
+        }
+        if setting.cgroup_root.is_none() {
+            setting.cgroup_root = self.cgroup_root.clone();
+        }
+        setting.accounting |= self.accounting;
+        setting.server_timing |= self.server_timing;
+        setting
+    }
+
+    /// Set up what the host needs before serving requests
+    pub(crate) fn prep_host(&self) {
         // Create runtime directory
         fs::create_dir_all(&self.runtime_dir).expect("Failed to initialized runtime directoy");
 
         if self.isolation {
+            if let Err(e) = self.prep_cgroups() {
+                panic!("{}", e);
+            }
             // FIXME preparing bridge in the host probably not require in code.
             // because we want to be able to run web server without root permission
-            net::prep_bridge("10.0.0.0/24".to_string());
-            signal::ignore_sigchld().expect("Abort: cuz dont want zombie");
+            net::prep_bridge(self.bridge_subnet.clone());
         }
+    }
 
-        let startup_message = format!(
-            "\n        ▒▒▒▒▒▒▒▒▓▓                                                                           \
-             \n    ▒▒▒▒░░░░░░██░░▓▓▓▓                                  ,d                 ,d                \
-             \n    ▒▒░░░░░░██▓▓░░░░▒▒▓▓                                88                 88                \
-             \n  ▒▒░░░░▓▓░░░░░░░░░░▒▒▓▓▓▓     8b,dPPYba,   ,adPPYba, MM88MMM ,adPPYYba, MM88MMM ,adPPYba,   \
-             \n  ▒▒░░░░░░░░░░░░░░░░▒▒▓▓▓▓     88P'    '8a a8'     '8a  88    **     `Y8   88   a8'     '8a  \
-             \n  ▒▒░░░░░░░░░░██░░▒▒▓▓▓▓▓▓     88       d8 8b       d8  88    ,adPPPPP88   88   8b       d8  \
-             \n  ▒▒░░░░░░░░▓▓██▒▒▓▓▓▓▓▓       88b,   ,a8' '8a,   ,a8'  88,   88,    ,88   88,  '8a,   ,a8'  \
-             \n  ▓▓░░░░░░░░░░▒▒▓▓▓▓▓▓         88`YbbdP''   `'YbbdP''   'Y888 `'8bbdP'Y8   'Y888 `'YbbdP''   \
-             \n    ▓▓▓▓▒▒▒▒▓▓▓▓▓▓             88                                                            \
-             \n        ▓▓▓▓▓▓▓▓               88    Listening on {}",
-            address
-        );
-        println!("{}", startup_message);
-
-        let protected_runtime_dir = Arc::new(Mutex::new(self.runtime_dir.clone()));
-
-        for stream in listener.incoming() {
-            let server = self.clone();
-            let stream = stream.unwrap();
-            let arc_runtime_dir = Arc::clone(&protected_runtime_dir);
-            thread::spawn(move || {
-                if server.isolation {
-                    let runtime_dir = arc_runtime_dir.lock().unwrap();
-                    let rootfs = prep::fs_prep(&runtime_dir);
-                    std::mem::drop(runtime_dir); // unlock mutex
-                    server.handle_connection_with_isolation(stream, rootfs);
-                } else {
-                    server.handle_connection(stream);
+    fn serve(&self, stream: Stream, protected_runtime_dir: &Mutex<String>) {
+        let stream = match (self.tls_terminator(), stream) {
+            (Some(terminator), Stream::Tcp(stream)) => {
+                let client = stream.peer_addr().ok();
+                // kept by the terminator for the encrypted stream
+                if stream.set_write_timeout(Some(self.write_timeout)).is_err() {
+                    return;
                 }
-            });
+                match terminator.terminate(stream) {
+                    // the handshake happens on the first read
+                    Ok(stream) => Stream::Tls(stream, client),
+                    Err(_) => return,
+                }
+            }
+            (_, stream) => stream,
+        };
+        if stream.set_write_timeout(Some(self.write_timeout)).is_err() {
+            return;
+        }
+
+        if self.isolation {
+            let runtime_dir = protected_runtime_dir.lock().unwrap();
+            let rootfs = prep::fs_prep(&runtime_dir);
+            std::mem::drop(runtime_dir); // unlock mutex
+            self.handle_connection_with_isolation(stream, rootfs);
+        } else {
+            self.handle_connection(stream);
         }
     }
 
-    fn handle_connection(&self, mut stream: TcpStream) {
-        let ref mut buffer = [0; 1024];
-        if let Ok(0) = stream.read(buffer) {
-            self.handle_req_error(&stream, "Socket closed");
+    /// Turn a connection away when the worker pool is saturated. Runs on the
+    /// accept loop, so the write must not block for long.
+    fn reject_connection(&self, mut stream: Stream) {
+        if let (Some(_), Stream::Tcp(_)) = (self.tls_terminator(), &stream) {
+            // answering needs a handshake, too costly here
             return;
         }
+        let _ = stream.set_write_timeout(Some(Duration::from_millis(100)));
+        let pres = PotatoResponse::new()
+            .set_status(StatusCode::SERVICE_UNAVAILABLE)
+            .add_header("Retry-After", "1")
+            .add_header("Connection", "close");
+        let _ = self.write_response(&mut stream, pres, HttpRequestMethod::GET);
+    }
 
-        let len = &self.handlers.len();
-        let mut count: usize = 1;
+    /// Start an entry for the routed `req` if there is an access log
+    /// or metrics to record it in
+    pub(crate) fn log_entry(
+        &self,
+        client: Option<SocketAddr>,
+        req: &PotatoRequest,
+    ) -> Option<LogEntry> {
+        if self.access_log.is_none() && self.metrics.is_none() {
+            return None;
+        }
+        Some(LogEntry::new(client, req))
+    }
 
-        let req = PotatoRequest::from_raw_req(buffer);
+    /// Log a request answered with `status` in `bytes`
+    pub(crate) fn log_access(&self, entry: Option<LogEntry>, status: u16, bytes: usize) {
+        if let Some(entry) = entry {
+            self.record(entry.finish(status, bytes));
+        }
+    }
 
-        for (route, handler, _) in &self.handlers {
-            let head = format!("{} {} HTTP/1.1", route.method, route.path);
-            if buffer.starts_with(head.as_bytes()) {
-                let pres = handler(req);
-                self.write_response(stream, pres);
-                break;
-            } else if len.eq(&count) {
-                let (d_handler, _) = self.default_handler.clone().unwrap();
-                let pres = d_handler(req);
-                self.write_response(stream, pres);
-                break;
-            }
-            count += 1;
+    /// Write a finished entry to the access log and metrics
+    fn record(&self, entry: LogEntry) {
+        if let Some(metrics) = &self.metrics {
+            metrics.observe(&entry);
+        }
+        if let Some(log) = &self.access_log {
+            log.log(&entry);
         }
     }
 
-    fn handle_connection_with_isolation(&self, mut stream: TcpStream, rootfs: String) {
-        let ref mut buffer = [0; 1024];
-        if let Ok(0) = stream.read(buffer) {
-            self.handle_req_error(&stream, "Socket closed");
-            return;
+    /// Count a sandbox that couldn't be cloned
+    pub(crate) fn clone_failed(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.clone_failed();
+        }
+    }
+
+    /// Count a sandbox started, until `log_sandbox` sees it exit
+    pub(crate) fn sandbox_started(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.sandbox_started();
         }
+    }
 
-        let len = &self.handlers.len();
-        let mut count: usize = 1;
+    /// Log a request answered by `sandbox`, waiting for the sandbox
+    /// to exit and report. Status 0 stands for a sandbox that died
+    /// without answering.
+    pub(crate) fn log_sandbox(
+        &self,
+        entry: Option<LogEntry>,
+        sandbox: Option<(usize, i32)>,
+        report: Option<ReportReader>,
+    ) {
+        let mut entry = match entry {
+            Some(entry) => entry,
+            None => return,
+        };
+        entry.sandbox = sandbox;
+        let report = report.and_then(ReportReader::wait);
+        if let Some(metrics) = &self.metrics {
+            metrics.sandbox_exited();
+            if let Some(report) = report {
+                if let Some(setup) = report.setup {
+                    metrics.observe_setup(setup);
+                }
+                metrics.observe_teardown(report.teardown);
+            }
+        }
+        match report {
+            Some(report) => {
+                entry.usage = report.usage;
+                self.record(entry.finish_at(report.status, report.bytes, report.answered))
+            }
+            None => self.log_access(Some(entry), 0, 0),
+        }
+    }
 
-        let req = PotatoRequest::from_raw_req(buffer);
-        for (route, handler, opt_isolation) in &self.handlers {
-            let head = format!("{} {} HTTP/1.1", route.method, route.path);
+    /// Keep track of `sandbox` until it exits, returning what it takes to log it
+    pub(crate) fn track(
+        &self,
+        mut sandbox: Sandbox,
+    ) -> (Option<(usize, i32)>, Option<ReportReader>) {
+        let info = sandbox.dir_number().map(|dir| (dir, sandbox.pid()));
+        let report = sandbox.take_report();
+        self.sandbox_started();
+        self.inflight.track(sandbox);
+        (info, report)
+    }
 
-            if buffer.starts_with(head.as_bytes()) {
-                let mut isolation_setting = opt_isolation.clone().unwrap(); // safe unwrap
-                isolation_setting.rootfs_path = rootfs;
+    /// Serve requests off `stream` until either side asks to close,
+    /// the connection sits idle for too long or the request limit is hit.
+    /// Pipelined requests are answered in order they were received.
+    fn handle_connection(&self, stream: Stream) {
+        let client = stream.peer_addr();
+        let mut reader = RequestReader::new(stream, self.limits);
+        let mut served: usize = 0;
 
-                if let Err(strm) = isolate_req(stream, req, *handler, isolation_setting) {
-                    self.handle_req_error(&strm, "Isolation failure: clone init");
+        loop {
+            let mut req = match reader.read_request_timed(self.read_timeouts()) {
+                Ok(req) => req,
+                Err(e) => {
+                    self.handle_read_error(reader.get_mut(), e);
+                    break;
                 }
+            };
+            served += 1;
+            let keep_alive = req.keep_alive()
+                && served < self.max_keep_alive_requests
+                && !self.inflight.is_closing();
+            let method = req.method;
+            let routed = self.find_handler(&mut req);
+            let entry = self.log_entry(client, &req);
+
+            let pres = match routed {
+                Ok((Endpoint::Blocking(handler), _)) => self.run_handler(handler, req),
+                Ok((Endpoint::Async(_), _)) => unreachable!(),
+                Err(pres) => pres,
+            };
+            let keep_alive = keep_alive && !pres.closes_connection();
+            let connection = if keep_alive { "keep-alive" } else { "close" };
+            let pres = pres.set_header("Connection", connection);
+            let status = pres.status().as_u16();
+
+            let written = self.write_response(reader.get_mut(), pres, method);
+            self.log_access(entry, status, written.as_ref().map_or(0, |n| *n));
+            if written.is_err() || !keep_alive {
                 break;
-            } else if len.eq(&count) {
-                let (d_handler, opt_isolation) = self.default_handler.clone().unwrap();
-                let mut isolation_setting = opt_isolation.clone().unwrap(); // safe unwrap
-                isolation_setting.rootfs_path = rootfs;
-                if let Err(strm) = isolate_req(stream, req, d_handler, isolation_setting) {
-                    self.handle_req_error(&strm, "Isolation failure: clone init");
+            }
+        }
+    }
+
+    fn handle_connection_with_isolation(&self, stream: Stream, rootfs: String) {
+        let client = stream.peer_addr();
+        let (mut stream, mut req, pipelined) = match self.read_request(stream) {
+            Some(read) => read,
+            None => {
+                let _ = fs::remove_dir_all(&rootfs);
+                return;
+            }
+        };
+        // every response closes the connection here
+        if pipelined {
+            log_unanswered_pipelined(client);
+        }
+        let routed = self.find_handler(&mut req);
+        let entry = self.log_entry(client, &req);
+
+        match routed {
+            Ok((Endpoint::Blocking(handler), Some(isolation_setting))) => {
+                req.state = self.state.snapshot();
+                let mut isolation_setting = self.sandbox_setting(isolation_setting);
+                isolation_setting.rootfs_path = rootfs.clone();
+
+                match isolate_req(stream, req, handler, isolation_setting) {
+                    Ok(sandbox) => {
+                        let (sandbox, report) = self.track(sandbox);
+                        self.log_sandbox(entry, sandbox, report);
+                    }
+                    Err(mut strm) => {
+                        let _ = fs::remove_dir_all(&rootfs);
+                        self.clone_failed();
+                        self.handle_req_error(&mut strm, "Isolation failure: clone init");
+                        self.log_access(entry, 500, 0);
+                    }
                 }
-                break;
             }
-            count += 1;
+            // routes without isolation setting, e.g. metrics, run in the server
+            Ok((Endpoint::Blocking(handler), None)) => {
+                let _ = fs::remove_dir_all(&rootfs);
+                let method = req.method;
+                let pres = self
+                    .run_handler(handler, req)
+                    .set_header("Connection", "close");
+                let status = pres.status().as_u16();
+                let written = self.write_response(&mut stream, pres, method);
+                self.log_access(entry, status, written.unwrap_or(0));
+            }
+            Ok((Endpoint::Async(_), _)) => unreachable!(),
+            Err(pres) => {
+                let _ = fs::remove_dir_all(&rootfs);
+                let status = pres.status().as_u16();
+                let written = self.write_response(&mut stream, pres, req.method);
+                self.log_access(entry, status, written.unwrap_or(0));
+            }
+        }
+    }
+
+    /// Read a whole request off `stream`, answering with the appropriate
+    /// error status when the request is malformed or exceeds the limits.
+    /// Also tells whether requests were pipelined after it.
+    fn read_request(&self, stream: Stream) -> Option<(Stream, PotatoRequest, bool)> {
+        let mut reader = RequestReader::new(stream, self.limits);
+        match reader.read_request_timed(self.read_timeouts()) {
+            Ok(req) => {
+                let pipelined = reader.has_pipelined();
+                Some((reader.into_inner(), req, pipelined))
+            }
+            Err(e) => {
+                self.handle_read_error(reader.get_mut(), e);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn read_timeouts(&self) -> ReadTimeouts {
+        ReadTimeouts {
+            idle: self.keep_alive_timeout,
+            header: self.header_read_timeout,
+            body: self.body_read_timeout,
+        }
+    }
+
+    /// Run `handler`, on a thread of its own when there is a handler timeout.
+    /// A panicking handler is answered for with `500`.
+    fn run_handler(&self, handler: PotatoRequestHandler, req: PotatoRequest) -> PotatoResponse {
+        let timeout = match self.handler_timeout {
+            Some(timeout) => timeout,
+            None => {
+                return panic::catch_unwind(AssertUnwindSafe(|| handler.handle(req)))
+                    .unwrap_or_else(|_| {
+                        error_response(StatusCode::INTERNAL_SERVER_ERROR, "Handler failure")
+                    })
+            }
+        };
+        let (tx, rx) = mpsc::channel();
+        let spawned = thread::Builder::new()
+            .name("potato-handler".to_string())
+            .spawn(move || {
+                let _ = tx.send(handler.handle(req));
+            });
+        if spawned.is_err() {
+            return error_response(StatusCode::SERVICE_UNAVAILABLE, "Handler thread");
+        }
+        match rx.recv_timeout(timeout) {
+            Ok(pres) => pres,
+            Err(RecvTimeoutError::Timeout) => {
+                error_response(StatusCode::GATEWAY_TIMEOUT, "Handler timed out")
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, "Handler failure")
+            }
+        }
+    }
+
+    fn handle_read_error(&self, stream: &mut Stream, e: RequestError) {
+        if let Some(status) = e.status() {
+            self.handle_req_error_with_status(stream, status, &e.to_string());
+        }
+    }
+
+    /// Route `req` to its handler wrapped in the middlewares, filling in
+    /// the captured path parameters and state and running the filters.
+    /// Unroutable or filtered out requests get the response to send back instead.
+    pub(crate) fn find_handler(
+        &self,
+        req: &mut PotatoRequest,
+    ) -> Result<(Endpoint, Option<IsolationSetting>), PotatoResponse> {
+        let target = match self.router.lookup(req.method, &req.path) {
+            RouteMatch::Found(target, params) => {
+                req.params = params;
+                target.clone()
+            }
+            RouteMatch::MethodNotAllowed(allowed) => {
+                let allowed: Vec<String> = allowed.iter().map(|m| m.to_string()).collect();
+                return Err(PotatoResponse::new()
+                    .set_status(StatusCode::METHOD_NOT_ALLOWED)
+                    .add_header("Allow", &allowed.join(", ")));
+            }
+            RouteMatch::NotFound => match &self.default_handler {
+                Some(default_handler) => default_handler.clone(),
+                None => return Err(PotatoResponse::new().set_status(StatusCode::NOT_FOUND)),
+            },
+        };
+
+        let Target {
+            path,
+            endpoint,
+            isolation: opt_isolation,
+        } = target;
+        req.route = path;
+        req.state = self.state.clone();
+        for filter in &self.filters {
+            if let Some(pres) = filter.filter(req) {
+                return Err(pres);
+            }
+        }
+
+        match endpoint {
+            Endpoint::Blocking(handler) if !self.middlewares.is_empty() => {
+                let chain = Chain::new(self.middlewares.clone(), handler);
+                Ok((Endpoint::Blocking(Arc::new(chain)), opt_isolation))
+            }
+            endpoint => Ok((endpoint, opt_isolation)),
         }
     }
 
-    fn write_response(&self, mut stream: TcpStream, response: PotatoResponse) {
-        let res = response.to_http_response();
-        stream.write(&res).unwrap();
-        stream.flush().unwrap();
+    /// Write `response` to `stream`, returning the number of bytes written
+    fn write_response(
+        &self,
+        stream: &mut impl Write,
+        response: PotatoResponse,
+        method: HttpRequestMethod,
+    ) -> io::Result<usize> {
+        let res = response.to_http_response(method);
+        stream.write_all(&res)?;
+        stream.flush()?;
+        Ok(res.len())
     }
 
     pub fn get_header(&self, s: &str, ignore: &str) -> HashMap<String, String> {
@@ -215,13 +1025,147 @@ impl PotatoServer {
         header
     }
 
-    fn handle_req_error(&self, mut stream: &TcpStream, message: &str) {
-        let header = format!("Content-Length: {}", message.len());
-        let response = format!(
-            "HTTP/1.1 500 Internal Server Error\r\n{}\r\n\r\n{}",
-            header, message
-        );
-        stream.write(response.as_bytes()).unwrap();
-        stream.flush().unwrap();
+    fn handle_req_error(&self, stream: &mut Stream, message: &str) {
+        self.handle_req_error_with_status(stream, StatusCode::INTERNAL_SERVER_ERROR, message);
+    }
+
+    fn handle_req_error_with_status(&self, stream: &mut Stream, status: StatusCode, message: &str) {
+        let response = error_response(status, message);
+        // the peer may already be gone, nothing left to do on failure
+        let _ = self.write_response(stream, response, HttpRequestMethod::GET);
+    }
+}
+
+/// Accepted connection along with the server config it is served with
+type Connection = (Arc<PotatoServer>, Stream, ConnectionGuard);
+
+/// Hand connections accepted on `listener` to the pool or to a new thread,
+/// each served with the server config current when it was accepted
+fn accept_loop(
+    listener: &Listener,
+    current: &RwLock<Arc<PotatoServer>>,
+    pool: Option<&WorkerPool<Connection>>,
+    protected_runtime_dir: &Arc<Mutex<String>>,
+) {
+    loop {
+        let stream = listener.accept();
+        let server = Arc::clone(&current.read().unwrap());
+        if server.inflight.is_closing() {
+            return;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                if !listener::is_connection_error(&e) {
+                    eprintln!("Failed accepting on {}: {}", listener, e);
+                    thread::sleep(listener::ACCEPT_BACKOFF);
+                }
+                continue;
+            }
+        };
+        let guard = server.inflight.enter();
+        match pool {
+            Some(pool) => {
+                if let Err((server, stream, _guard)) = pool.try_execute((server, stream, guard)) {
+                    server.reject_connection(stream);
+                }
+            }
+            None => {
+                let arc_runtime_dir = Arc::clone(protected_runtime_dir);
+                thread::spawn(move || {
+                    let _guard = guard;
+                    server.serve(stream, &arc_runtime_dir)
+                });
+            }
+        }
     }
 }
+
+/// Mark the server closing and stop accepting on `listener_fds` upon SIGTERM
+/// or SIGINT, so that `start` or `start_async`, as told by `serves_async`,
+/// drains and returns, and swap in a reloaded server upon SIGHUP
+pub(crate) fn handle_signals(
+    current: Arc<RwLock<Arc<PotatoServer>>>,
+    listener_fds: Vec<RawFd>,
+    serves_async: bool,
+) {
+    let sigs = [libc::SIGTERM, libc::SIGINT, libc::SIGHUP, libc::SIGUSR2];
+    let mut siginfo =
+        sighook::iterator::Signals::new(sigs).expect("Failed installing signal handlers");
+
+    thread::spawn(move || {
+        for sig in siginfo.forever() {
+            let server = Arc::clone(&current.read().unwrap());
+            match sig {
+                libc::SIGHUP => {
+                    let reload = match &server.reload_handler {
+                        Some(reload) => reload,
+                        None => continue,
+                    };
+                    let next = match panic::catch_unwind(AssertUnwindSafe(|| reload())) {
+                        Ok(next) => server.reloaded(next, serves_async),
+                        Err(_) => Err("reload handler panicked".to_string()),
+                    };
+                    match next {
+                        Ok(next) => {
+                            *current.write().unwrap() = Arc::new(next);
+                            println!("Reloaded server");
+                        }
+                        Err(e) => eprintln!("Reload failed, keeping the running server: {}", e),
+                    }
+                }
+                libc::SIGUSR2 => {
+                    if let Some(log) = &server.access_log {
+                        if let Err(e) = log.reopen() {
+                            eprintln!("Failed reopening access log: {}", e);
+                        }
+                    }
+                }
+                _ => {
+                    server.inflight.close();
+                    // wakes up the accept loops blocked on the listeners
+                    for fd in &listener_fds {
+                        unsafe { libc::shutdown(*fd, libc::SHUT_RD) };
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Note requests pipelined after one answered with `Connection: close`,
+/// e.g. by a sandbox, which the client has to send again
+pub(crate) fn log_unanswered_pipelined(client: Option<SocketAddr>) {
+    let client = client.map_or("a Unix socket".to_string(), |c| c.to_string());
+    eprintln!(
+        "Closing connection from {} with pipelined requests unanswered",
+        client
+    );
+}
+
+/// Response closing the connection after reporting an error
+pub(crate) fn error_response(status: StatusCode, message: &str) -> PotatoResponse {
+    PotatoResponse::new()
+        .set_status(status)
+        .add_header("Connection", "close")
+        .add_body(message.as_bytes().to_vec())
+}
+
+pub(crate) fn print_banner(listeners: &[Listener]) {
+    let address: Vec<String> = listeners.iter().map(|l| l.to_string()).collect();
+    let address = address.join(", ");
+    let startup_message = format!(
+        "\n        ▒▒▒▒▒▒▒▒▓▓                                                                           \
+         \n    ▒▒▒▒░░░░░░██░░▓▓▓▓                                  ,d                 ,d                \
+         \n    ▒▒░░░░░░██▓▓░░░░▒▒▓▓                                88                 88                \
+         \n  ▒▒░░░░▓▓░░░░░░░░░░▒▒▓▓▓▓     8b,dPPYba,   ,adPPYba, MM88MMM ,adPPYYba, MM88MMM ,adPPYba,   \
+         \n  ▒▒░░░░░░░░░░░░░░░░▒▒▓▓▓▓     88P'    '8a a8'     '8a  88    **     `Y8   88   a8'     '8a  \
+         \n  ▒▒░░░░░░░░░░██░░▒▒▓▓▓▓▓▓     88       d8 8b       d8  88    ,adPPPPP88   88   8b       d8  \
+         \n  ▒▒░░░░░░░░▓▓██▒▒▓▓▓▓▓▓       88b,   ,a8' '8a,   ,a8'  88,   88,    ,88   88,  '8a,   ,a8'  \
+         \n  ▓▓░░░░░░░░░░▒▒▓▓▓▓▓▓         88`YbbdP''   `'YbbdP''   'Y888 `'8bbdP'Y8   'Y888 `'YbbdP''   \
+         \n    ▓▓▓▓▒▒▒▒▓▓▓▓▓▓             88                                                            \
+         \n        ▓▓▓▓▓▓▓▓               88    Listening on {}",
+        address
+    );
+    println!("{}", startup_message);
+}
@@ -0,0 +1,98 @@
+use crate::isolation::Sandbox;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long a killed sandbox gets to exit before its rootfs is removed anyway
+const KILL_GRACE: Duration = Duration::from_secs(1);
+
+/// Connections and sandboxes a server has to wait for before exiting
+#[derive(Default)]
+pub struct Inflight {
+    closing: AtomicBool,
+    connections: Mutex<usize>,
+    sandboxes: Mutex<Vec<Sandbox>>,
+}
+
+/// Marks a connection in flight until dropped
+pub struct ConnectionGuard(Arc<Inflight>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        *self.0.connections.lock().unwrap() -= 1;
+    }
+}
+
+impl Inflight {
+    pub fn new() -> Inflight {
+        Inflight::default()
+    }
+
+    /// Whether the server stopped accepting and is draining
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+    }
+
+    pub fn enter(self: &Arc<Self>) -> ConnectionGuard {
+        *self.connections.lock().unwrap() += 1;
+        ConnectionGuard(Arc::clone(self))
+    }
+
+    /// Keep `sandbox` around until it exits on its own or is killed by `drain`
+    pub fn track(&self, sandbox: Sandbox) {
+        let mut sandboxes = self.sandboxes.lock().unwrap();
+        sandboxes.retain(|sandbox| !sandbox.has_exited());
+        sandboxes.push(sandbox);
+    }
+
+    /// Forget the sandboxes that exited every `POLL_INTERVAL`, which
+    /// removes their cgroups, until the server starts draining
+    pub fn reap(&self) {
+        while !self.is_closing() {
+            thread::sleep(POLL_INTERVAL);
+            let mut sandboxes = self.sandboxes.lock().unwrap();
+            sandboxes.retain(|sandbox| !sandbox.has_exited());
+        }
+    }
+
+    /// Wait for every connection and sandbox to finish until `deadline`,
+    /// then kill the remaining sandboxes and clean up after them.
+    /// Connections still open past the deadline are left to die with the process.
+    pub fn drain(&self, deadline: Instant) {
+        loop {
+            let connections = *self.connections.lock().unwrap();
+            let mut sandboxes = self.sandboxes.lock().unwrap();
+            sandboxes.retain(|sandbox| !sandbox.has_exited());
+            if (connections == 0 && sandboxes.is_empty()) || Instant::now() >= deadline {
+                break;
+            }
+            std::mem::drop(sandboxes); // unlock mutex
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        let sandboxes: Vec<Sandbox> = self
+            .sandboxes
+            .lock()
+            .unwrap()
+            .drain(..)
+            .filter(|sandbox| !sandbox.has_exited())
+            .collect();
+        for sandbox in &sandboxes {
+            let _ = sandbox.kill();
+        }
+        let grace = Instant::now() + KILL_GRACE;
+        for sandbox in sandboxes {
+            while !sandbox.has_exited() && Instant::now() < grace {
+                thread::sleep(POLL_INTERVAL);
+            }
+            eprintln!("Killed sandbox {} still running at shutdown", sandbox.pid());
+            sandbox.cleanup();
+        }
+    }
+}
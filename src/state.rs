@@ -0,0 +1,85 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+type AnyValue = Arc<dyn Any + Send + Sync>;
+
+/// State that can be handed to isolated handlers.
+///
+/// An isolated handler runs in a cloned process holding a copy of the
+/// server memory, so whatever it changes never reaches the server, and a
+/// lock held by another thread at clone time stays locked forever in the
+/// copy. `snapshot` is taken on the server side right before the clone and
+/// should return plain data, e.g. read the current values out of a `Mutex`.
+pub trait Snapshot: Send + Sync + 'static {
+    fn snapshot(&self) -> Self
+    where
+        Self: Sized;
+}
+
+/// Application state shared with handlers, one value per type
+#[derive(Clone, Default)]
+pub struct AppState {
+    entries: HashMap<TypeId, StateEntry>,
+}
+
+#[derive(Clone)]
+struct StateEntry {
+    value: AnyValue,
+    snapshot: Option<fn(&AnyValue) -> AnyValue>,
+}
+
+impl AppState {
+    pub fn new() -> AppState {
+        AppState {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Add state only visible to handlers running in the server process
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) {
+        let entry = StateEntry {
+            value: Arc::new(value),
+            snapshot: None,
+        };
+        self.entries.insert(TypeId::of::<T>(), entry);
+    }
+
+    /// Add state visible to all handlers, isolated handlers get a snapshot
+    pub fn insert_snapshot<T: Snapshot>(&mut self, value: T) {
+        let entry = StateEntry {
+            value: Arc::new(value),
+            snapshot: Some(snapshot_of::<T>),
+        };
+        self.entries.insert(TypeId::of::<T>(), entry);
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        self.entries
+            .get(&TypeId::of::<T>())
+            .and_then(|entry| entry.value.clone().downcast::<T>().ok())
+    }
+
+    /// State to hand over to an isolated handler:
+    /// a snapshot of every `Snapshot` value and nothing else
+    pub fn snapshot(&self) -> AppState {
+        let entries = self
+            .entries
+            .iter()
+            .filter_map(|(type_id, entry)| {
+                let snapshot = entry.snapshot?;
+                let entry = StateEntry {
+                    value: snapshot(&entry.value),
+                    snapshot: entry.snapshot,
+                };
+                Some((*type_id, entry))
+            })
+            .collect();
+        AppState { entries }
+    }
+}
+
+fn snapshot_of<T: Snapshot>(value: &AnyValue) -> AnyValue {
+    let value = value.downcast_ref::<T>().unwrap(); // safe unwrap: keyed by TypeId of T
+    Arc::new(value.snapshot())
+}
@@ -0,0 +1,69 @@
+use crate::handler::Handler;
+use crate::request::PotatoRequest;
+use crate::response::PotatoResponse;
+use crate::status::StatusCode;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Handler serving the files under a directory.
+///
+/// Register it on a route ending with a `*path` wildcard, e.g.
+/// `/assets/*path`, the captured remainder is looked up under `root`.
+/// Request paths come with dot segments resolved so they can't climb
+/// out of `root`. A directory is served through its `index.html`.
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    root: PathBuf,
+}
+
+impl StaticFiles {
+    pub fn new(root: &str) -> StaticFiles {
+        StaticFiles {
+            root: PathBuf::from(root),
+        }
+    }
+}
+
+impl Handler for StaticFiles {
+    fn handle(&self, req: PotatoRequest) -> PotatoResponse {
+        let rest = req.params.get("path").map(String::as_str).unwrap_or("");
+        let mut path = self.root.join(rest.trim_start_matches('/'));
+        if path.is_dir() {
+            path.push("index.html");
+        }
+
+        match fs::read(&path) {
+            Ok(contents) => PotatoResponse::new()
+                .set_status(StatusCode::OK)
+                .set_header("Content-Type", content_type(&path))
+                .add_body(contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                PotatoResponse::new().set_status(StatusCode::NOT_FOUND)
+            }
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                PotatoResponse::new().set_status(StatusCode::FORBIDDEN)
+            }
+            Err(_) => PotatoResponse::new().set_status(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,427 @@
+use crate::isolation::{close_fds_except, HOST_SIGNALS};
+use libpotato::nix;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{self, SigHandler};
+use nix::sys::socket::{
+    recvmsg, sendmsg, socketpair, AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags,
+    SockFlag, SockType,
+};
+use nix::sys::uio::IoVec;
+use nix::sys::wait::waitpid;
+use nix::unistd::{self, ForkResult, Pid};
+use rustls::crypto::ring::{default_provider, sign};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::{process, thread};
+
+/// Control message replacing the certificates, one
+/// `server name \0 cert path \0 key path \0` per certificate, answered
+/// with the load error if any
+const LOAD_CERTIFICATES: u8 = b'C';
+/// Control message passing a TCP connection and the plaintext socket it
+/// is relayed to as `SCM_RIGHTS`
+const TERMINATE: u8 = b'T';
+/// Relays the terminator runs at most, one thread each. Connections past
+/// it are closed right away, a worker pool of the server usually turns
+/// them away before.
+const MAX_RELAYS: usize = 1024;
+
+/// Certificates the server terminates TLS with.
+///
+/// Clients are served the certificate registered for the name they ask
+/// for through SNI, or the default one when they ask for none or an
+/// unknown name. Only `http/1.1` is offered through ALPN.
+///
+/// Only the paths are kept here, the certificates are loaded by the
+/// terminator process of the server, see `PotatoServer::set_tls`.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// `(server name, cert path, key path)`, the default one has an empty name
+    certificates: Vec<(String, String, String)>,
+}
+
+impl TlsConfig {
+    /// Use the PEM certificate chain in `cert_path` and the PEM private key
+    /// in `key_path` as default certificate. The files are only checked to
+    /// be readable, they are parsed by the terminator.
+    pub fn new(cert_path: &str, key_path: &str) -> io::Result<TlsConfig> {
+        TlsConfig {
+            certificates: Vec::new(),
+        }
+        .add_certificate("", cert_path, key_path)
+    }
+
+    /// Serve the certificate in `cert_path` to clients asking for `server_name`
+    pub fn add_sni_certificate(
+        self,
+        server_name: &str,
+        cert_path: &str,
+        key_path: &str,
+    ) -> io::Result<TlsConfig> {
+        assert!(
+            !server_name.is_empty(),
+            "[{}] empty server name",
+            server_name
+        );
+        self.add_certificate(&server_name.to_ascii_lowercase(), cert_path, key_path)
+    }
+
+    fn add_certificate(
+        mut self,
+        server_name: &str,
+        cert_path: &str,
+        key_path: &str,
+    ) -> io::Result<TlsConfig> {
+        for path in [cert_path, key_path].iter() {
+            File::open(path).map_err(|e| io::Error::new(e.kind(), format!("[{}] {}", path, e)))?;
+        }
+        self.certificates.retain(|(name, _, _)| name != server_name);
+        self.certificates.push((
+            server_name.to_string(),
+            cert_path.to_string(),
+            key_path.to_string(),
+        ));
+        Ok(self)
+    }
+}
+
+/// Process loading the certificates and running the TLS sessions, so that
+/// the server, and the sandboxes cloned from it, never hold a private key
+/// and only get the decrypted streams. It exits once its handle is dropped,
+/// e.g. with the server, and its connections are closed.
+#[derive(Debug)]
+pub(crate) struct Terminator {
+    pid: Pid,
+    /// `SOCK_SEQPACKET` socket, one message per request
+    control: OwnedFd,
+    /// pairs a certificate request with its answer
+    requests: Mutex<()>,
+}
+
+impl Terminator {
+    /// Fork the terminator. Forking is only safe before the server starts
+    /// any thread, certificates are changed later on with `load`.
+    pub(crate) fn spawn() -> io::Result<Terminator> {
+        let (control, terminator_end) = socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )
+        .map_err(io::Error::other)?;
+        let control = unsafe { OwnedFd::from_raw_fd(control) };
+        let terminator_end = unsafe { OwnedFd::from_raw_fd(terminator_end) };
+        match unsafe { unistd::fork() }.map_err(io::Error::other)? {
+            ForkResult::Child => run_terminator(terminator_end.as_raw_fd()),
+            ForkResult::Parent { child } => Ok(Terminator {
+                pid: child,
+                control,
+                requests: Mutex::new(()),
+            }),
+        }
+    }
+
+    /// Replace the certificates with those of `tls`. The previous ones
+    /// are kept when any fails to load, connections already handed over
+    /// keep theirs.
+    pub(crate) fn load(&self, tls: &TlsConfig) -> io::Result<()> {
+        let mut request = vec![LOAD_CERTIFICATES];
+        for (server_name, cert_path, key_path) in &tls.certificates {
+            request.extend(format!("{}\0{}\0{}\0", server_name, cert_path, key_path).bytes());
+        }
+        let mut answer = [0; 4096];
+
+        let _lock = self.requests.lock().unwrap();
+        sendmsg(
+            self.control.as_raw_fd(),
+            &[IoVec::from_slice(&request)],
+            &[],
+            MsgFlags::empty(),
+            None,
+        )
+        .map_err(io::Error::other)?;
+        let n = recvmsg(
+            self.control.as_raw_fd(),
+            &[IoVec::from_mut_slice(&mut answer)],
+            None,
+            MsgFlags::empty(),
+        )
+        .map_err(io::Error::other)?
+        .bytes;
+        match &answer[..n] {
+            [0] => Ok(()),
+            [] => Err(io::Error::other("TLS terminator exited")),
+            error => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                String::from_utf8_lossy(error).into_owned(),
+            )),
+        }
+    }
+
+    /// Hand the TLS connection `stream` to the terminator, the returned
+    /// socket carries its decrypted stream. Timeouts set on `stream` are
+    /// kept by the terminator.
+    pub(crate) fn terminate(&self, stream: TcpStream) -> io::Result<UnixStream> {
+        stream.set_nonblocking(false)?;
+        let (plaintext, relay) = UnixStream::pair()?;
+        let fds = [stream.as_raw_fd(), relay.as_raw_fd()];
+        sendmsg(
+            self.control.as_raw_fd(),
+            &[IoVec::from_slice(&[TERMINATE])],
+            &[ControlMessage::ScmRights(&fds)],
+            MsgFlags::empty(),
+            None,
+        )
+        .map_err(io::Error::other)?;
+        Ok(plaintext)
+    }
+}
+
+impl Drop for Terminator {
+    fn drop(&mut self) {
+        // the terminator sees EOF on its control socket once this is
+        // closed, but may still relay connections for a while
+        let pid = self.pid;
+        thread::spawn(move || waitpid(pid, None));
+    }
+}
+
+/// Main of the terminator process, serves the requests sent on `control`
+fn run_terminator(control: RawFd) -> ! {
+    // exits on EOF on `control` instead, e.g. after every connection was
+    // drained on shutdown
+    for sig in &HOST_SIGNALS {
+        unsafe { signal::signal(*sig, SigHandler::SigIgn) }.unwrap();
+    }
+    // e.g. the listeners of the server
+    close_fds_except(&[0, 1, 2, control]);
+
+    let mut config = None;
+    let mut relays = Vec::new();
+    let mut message = vec![0; 64 * 1024];
+    loop {
+        let mut cmsg = nix::cmsg_space!([RawFd; 2]);
+        let received = match recvmsg(
+            control,
+            &[IoVec::from_mut_slice(&mut message)],
+            Some(&mut cmsg),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        ) {
+            Ok(received) => received,
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Err(_) => break,
+        };
+        let fds: Vec<RawFd> = received
+            .cmsgs()
+            .flat_map(|cmsg| match cmsg {
+                ControlMessageOwned::ScmRights(fds) => fds,
+                _ => Vec::new(),
+            })
+            .collect();
+        let fds: Vec<OwnedFd> = fds
+            .into_iter()
+            .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+            .collect();
+
+        match message[..received.bytes].split_first() {
+            None => break,
+            Some((&LOAD_CERTIFICATES, request)) => {
+                let answer = match load_certificates(request) {
+                    Ok(loaded) => {
+                        config = Some(loaded);
+                        vec![0]
+                    }
+                    Err(e) => e.to_string().into_bytes(),
+                };
+                let _ = sendmsg(
+                    control,
+                    &[IoVec::from_slice(&answer)],
+                    &[],
+                    MsgFlags::empty(),
+                    None,
+                );
+            }
+            Some((&TERMINATE, _)) => {
+                let mut fds = fds.into_iter();
+                let (stream, plaintext, config) = match (fds.next(), fds.next(), &config) {
+                    (Some(stream), Some(plaintext), Some(config)) => {
+                        (stream, plaintext, Arc::clone(config))
+                    }
+                    _ => continue,
+                };
+                relays.retain(|relay: &thread::JoinHandle<()>| !relay.is_finished());
+                if relays.len() >= MAX_RELAYS {
+                    eprintln!(
+                        "TLS terminator running {} relays, closing connection",
+                        MAX_RELAYS
+                    );
+                    continue;
+                }
+                relays.push(thread::spawn(move || {
+                    relay(config, TcpStream::from(stream), UnixStream::from(plaintext))
+                }));
+            }
+            Some(_) => {}
+        }
+    }
+
+    for relay in relays {
+        let _ = relay.join();
+    }
+    process::exit(0);
+}
+
+/// Load the certificates of a `LOAD_CERTIFICATES` request
+fn load_certificates(request: &[u8]) -> io::Result<Arc<ServerConfig>> {
+    let request = String::from_utf8_lossy(request);
+    let fields: Vec<&str> = request.split_terminator('\0').collect();
+    if fields.is_empty() || !fields.len().is_multiple_of(3) {
+        return Err(io::Error::other("malformed certificate request"));
+    }
+    let mut by_name = HashMap::new();
+    for certificate in fields.chunks(3) {
+        let key = load_certified_key(certificate[1], certificate[2])?;
+        by_name.insert(certificate[0].to_string(), key);
+    }
+
+    let resolver = Resolver {
+        default: by_name.get("").cloned(),
+        by_name,
+    };
+    let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap() // safe unwrap: ring supports the default versions
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+#[derive(Debug)]
+struct Resolver {
+    default: Option<Arc<CertifiedKey>>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .filter(|name| !name.is_empty())
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+/// Relay a TLS connection until either side closes it. The decrypted
+/// stream goes to `plaintext`, whose EOF ends the session with `close_notify`.
+fn relay(config: Arc<ServerConfig>, mut stream: TcpStream, mut plaintext: UnixStream) {
+    let mut conn = match ServerConnection::new(config) {
+        Ok(conn) => conn,
+        Err(_) => return,
+    };
+    let mut buf = [0; 16 * 1024];
+    let mut stream_open = true;
+    loop {
+        while conn.wants_write() {
+            if conn.write_tls(&mut stream).is_err() {
+                return;
+            }
+        }
+
+        let stream_events = match stream_open {
+            true => PollFlags::POLLIN,
+            false => PollFlags::empty(),
+        };
+        let mut fds = [
+            PollFd::new(stream.as_raw_fd(), stream_events),
+            PollFd::new(plaintext.as_raw_fd(), PollFlags::POLLIN),
+        ];
+        match poll(&mut fds, -1) {
+            Ok(_) => {}
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Err(_) => return,
+        }
+        let ready = |fd: &PollFd| fd.revents().is_some_and(|events| !events.is_empty());
+
+        if stream_open && ready(&fds[0]) {
+            match conn.read_tls(&mut stream) {
+                Ok(0) => {
+                    // the response may still be on its way
+                    stream_open = false;
+                    let _ = plaintext.shutdown(Shutdown::Write);
+                }
+                Ok(_) => {}
+                Err(_) => return,
+            }
+            if conn.process_new_packets().is_err() {
+                // sends the alert
+                let _ = conn.write_tls(&mut stream);
+                return;
+            }
+            loop {
+                match conn.reader().read(&mut buf) {
+                    // `close_notify` from the client
+                    Ok(0) => {
+                        let _ = plaintext.shutdown(Shutdown::Write);
+                        break;
+                    }
+                    Ok(n) => {
+                        if plaintext.write_all(&buf[..n]).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(_) => return,
+                }
+            }
+        }
+
+        if ready(&fds[1]) {
+            match plaintext.read(&mut buf) {
+                Ok(0) | Err(_) => {
+                    conn.send_close_notify();
+                    while conn.wants_write() {
+                        if conn.write_tls(&mut stream).is_err() {
+                            break;
+                        }
+                    }
+                    return;
+                }
+                Ok(n) => {
+                    if conn.writer().write_all(&buf[..n]).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> io::Result<Arc<CertifiedKey>> {
+    let invalid = |path: &str, e: &dyn std::fmt::Display| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("[{}] {}", path, e))
+    };
+
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(cert_path, &e))?;
+    if certs.is_empty() {
+        return Err(invalid(cert_path, &"no certificate found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| invalid(key_path, &e))?;
+    let key = sign::any_supported_type(&key).map_err(|e| invalid(key_path, &e))?;
+
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
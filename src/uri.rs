@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+
+/// Decode `%XX` escapes, fail on truncated or non hex escapes
+pub fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            // from_str_radix would take a sign too
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// Percent-decode a path and resolve its `.` and `..` segments.
+/// The result always starts with `/` and never climbs above it.
+pub fn decode_path(raw: &str) -> Option<String> {
+    let decoded = String::from_utf8(percent_decode(raw)?).ok()?;
+    if decoded.contains('\0') {
+        return None;
+    }
+
+    // RFC 3986 section 5.2.4, applied after decoding so that
+    // escaped dot segments like `%2e%2e` are removed as well
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let mut path = format!("/{}", segments.join("/"));
+    let trailing = decoded.ends_with('/') || decoded.ends_with("/.") || decoded.ends_with("/..");
+    if trailing && !segments.is_empty() {
+        path.push('/');
+    }
+    Some(path)
+}
+
+/// Parse an `application/x-www-form-urlencoded` query string.
+/// Repeated keys keep every value in order of appearance.
+pub fn parse_query(raw: &str) -> Option<HashMap<String, Vec<String>>> {
+    let mut query: HashMap<String, Vec<String>> = HashMap::new();
+    for pair in raw.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = match pair.find('=') {
+            Some(i) => (&pair[..i], &pair[i + 1..]),
+            None => (pair, ""),
+        };
+        query
+            .entry(decode_query_component(key)?)
+            .or_default()
+            .push(decode_query_component(value)?);
+    }
+    Some(query)
+}
+
+fn decode_query_component(s: &str) -> Option<String> {
+    let decoded = percent_decode(&s.replace('+', " "))?;
+    Some(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b%2Fc").unwrap(), b"a b/c");
+        assert_eq!(percent_decode("%e2%82%ac").unwrap(), "€".as_bytes());
+        assert!(percent_decode("%2").is_none());
+        assert!(percent_decode("%zz").is_none());
+        assert!(percent_decode("%+1").is_none());
+    }
+
+    #[test]
+    fn dot_segments() {
+        let cases = [
+            ("/", "/"),
+            ("", "/"),
+            ("/a/b/../c", "/a/c"),
+            ("/a/./b/", "/a/b/"),
+            ("/a/b/..", "/a/"),
+            ("//a///b", "/a/b"),
+            ("/../../etc/passwd", "/etc/passwd"),
+            ("/a/%2e%2e/%2E%2E/etc", "/etc"),
+            ("/a/%2e/b", "/a/b"),
+            ("/..", "/"),
+        ];
+        for (raw, path) in cases.iter() {
+            assert_eq!(decode_path(raw).as_deref(), Some(*path), "{}", raw);
+        }
+    }
+
+    #[test]
+    fn encoded_slashes() {
+        // decoded before resolving, `%2F` can't smuggle a `..` past it
+        assert_eq!(decode_path("/a%2Fb").as_deref(), Some("/a/b"));
+        assert_eq!(decode_path("/a/b%2F..%2F..%2Fc").as_deref(), Some("/c"));
+        assert_eq!(
+            decode_path("/static/..%2F..%2Fsecret").as_deref(),
+            Some("/secret")
+        );
+    }
+
+    #[test]
+    fn invalid_paths() {
+        assert!(decode_path("/a%00b").is_none());
+        assert!(decode_path("/%ff").is_none());
+        assert!(decode_path("/%4").is_none());
+    }
+
+    #[test]
+    fn query_strings() {
+        let query = parse_query("a=1&b=x+y&a=2&c&&d=%26").unwrap();
+        assert_eq!(query["a"], vec!["1", "2"]);
+        assert_eq!(query["b"], vec!["x y"]);
+        assert_eq!(query["c"], vec![""]);
+        assert_eq!(query["d"], vec!["&"]);
+        assert_eq!(query.len(), 4);
+        assert!(parse_query("a=%zz").is_none());
+        assert!(parse_query("").unwrap().is_empty());
+    }
+}